@@ -23,9 +23,12 @@
             src = ./.; # Assumes Cargo.toml is in the root
             cargoLock.lockFile = ./Cargo.lock;
 
-            # Native dependencies needed for midir (ALSA on Linux)
+            # Native PulseAudio volume control for Volume actions
+            buildFeatures = pkgs.lib.optionals pkgs.stdenv.isLinux [ "pulse" ];
+
+            # Native dependencies needed for midir (ALSA on Linux) and the pulse feature
             nativeBuildInputs = [ pkgs.pkg-config ];
-            buildInputs = [ pkgs.alsa-lib ] ++ pkgs.lib.optionals pkgs.stdenv.isLinux [ pkgs.udev ];
+            buildInputs = [ pkgs.alsa-lib ] ++ pkgs.lib.optionals pkgs.stdenv.isLinux [ pkgs.udev pkgs.libpulseaudio ];
           };
         in
         {
@@ -47,7 +50,7 @@
               rust-analyzer
               pkg-config
               alsa-lib
-            ] ++ lib.optionals stdenv.isLinux [ udev ];
+            ] ++ lib.optionals stdenv.isLinux [ udev libpulseaudio ];
 
             shellHook = ''
               ${self.checks.${system}.pre-commit-checks}/bin/install
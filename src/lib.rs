@@ -0,0 +1,5432 @@
+//! The MIDI-to-action engine behind the `midi-actions` daemon: config types and validation,
+//! the MIDI decoder, and [`Engine`], which turns raw messages into key presses and commands.
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+use enigo::{Axis as EnigoAxis, Coordinate, Direction, Enigo, Key, Keyboard, Mouse, Settings};
+#[cfg(target_os = "linux")]
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AttributeSet, BusType, EventType as EvdevEventType, InputEvent, InputId, Key as EvdevKey,
+    RelativeAxisType,
+};
+use log::{debug, error, info, trace, warn};
+use midir::{MidiOutput, MidiOutputConnection};
+use regex::Regex;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    env, fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+pub type Result<T, E = MidiActionError> = std::result::Result<T, E>;
+
+/// Everything the subcommands and the daemon can fail with
+#[derive(Debug, Error)]
+pub enum MidiActionError {
+    #[error(
+        "No config file found (looked for {}). Pass --config or create one with `midi-actions init`.",
+        .0.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    NoConfig(Vec<PathBuf>),
+    #[error("{} not found!", .0.display())]
+    ConfigNotFound(PathBuf),
+    #[error("{} already exists (use --force to overwrite)", .0.display())]
+    ConfigExists(PathBuf),
+    /// The file isn't valid TOML, JSON or YAML, or doesn't match the config format
+    #[error("{error}")]
+    ConfigParse {
+        path: PathBuf,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Every problem found by validation, sorted
+    #[error("Invalid config:\n  - {}", .0.join("\n  - "))]
+    InvalidConfig(Vec<String>),
+    #[error("Invalid config path {}", .0.display())]
+    InvalidConfigPath(PathBuf),
+    #[error(transparent)]
+    DeviceRegex(#[from] regex::Error),
+    #[error("No MIDI devices found.")]
+    NoDevices,
+    /// No port matched; `suggestion` is the port whose name is closest to `name`
+    #[error("Device '{name}' not found{}", port_hint(available, suggestion.as_deref()))]
+    DeviceNotFound {
+        name: String,
+        available: Vec<String>,
+        suggestion: Option<String>,
+    },
+    #[error("No MIDI port with index {index} ({available} available)")]
+    NoSuchPort { index: usize, available: usize },
+    #[error("No port selected")]
+    NoPortSelected,
+    #[error(transparent)]
+    MidiInit(#[from] midir::InitError),
+    #[error(transparent)]
+    PortInfo(#[from] midir::PortInfoError),
+    #[error("Connection failed: {0}")]
+    MidiConnect(String),
+    #[error("Failed to open thru port '{port}': {reason}")]
+    Thru { port: String, reason: String },
+    #[cfg(target_os = "linux")]
+    #[error("Failed to create the virtual keyboard: {0}")]
+    Uinput(io::Error),
+    /// /dev/uinput exists but the user may not open it
+    #[cfg(target_os = "linux")]
+    #[error(
+        "No permission to open /dev/uinput, which the virtual keyboard needs ({0}). To fix it:\n  \
+         - add yourself to the group that owns it (see `ls -l /dev/uinput`), e.g. \
+         `sudo usermod -aG input $USER`, then log out and back in\n  \
+         - or add a udev rule such as \
+         KERNEL==\"uinput\", MODE=\"0660\", GROUP=\"input\", OPTIONS+=\"static_node=uinput\" \
+         and run `sudo udevadm control --reload && sudo udevadm trigger`\n  \
+         - or try the config with --dry-run, which doesn't need a keyboard"
+    )]
+    UinputPermission(io::Error),
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[error("Virtual keyboard: {0}")]
+    Keyboard(String),
+    #[cfg(feature = "pulse")]
+    #[error("Volume control: {0}")]
+    Volume(String),
+    #[error("Failed to watch the config: {0}")]
+    Watch(notify::Error),
+    #[error(transparent)]
+    Signal(#[from] ctrlc::Error),
+    #[cfg(unix)]
+    #[error("Failed to listen on {}: {error}", path.display())]
+    Socket { path: PathBuf, error: io::Error },
+    /// `Engine::set_layer` with a name the config doesn't define
+    #[error("unknown layer '{0}'")]
+    UnknownLayer(String),
+    #[cfg(unix)]
+    #[error("unknown command '{0}' (try reload, layer <name> or status)")]
+    UnknownCommand(String),
+    #[error("the config was read from stdin, there is nothing to reload")]
+    StdinReload,
+    /// A `trigger` id or value that no MIDI message can carry
+    #[error(
+        "Can't trigger {control} with value {value} (ids and values are 0-127, pitch bend 0-16383)"
+    )]
+    InvalidTrigger { control: Control, value: u16 },
+    /// A triggered message that no mapping fired for
+    #[error("Nothing fired for {control} on channel {channel}")]
+    NotMapped { control: Control, channel: u8 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl MidiActionError {
+    /// A `DeviceNotFound` listing `available` and suggesting the closest of them to `name`
+    pub fn device_not_found(name: &str, available: Vec<String>) -> Self {
+        let suggestion = closest_port(name, &available).map(str::to_string);
+        MidiActionError::DeviceNotFound {
+            name: name.to_string(),
+            available,
+            suggestion,
+        }
+    }
+}
+
+// The end of a DeviceNotFound message: what to try instead
+fn port_hint(available: &[String], suggestion: Option<&str>) -> String {
+    if available.is_empty() {
+        return " (no MIDI ports available)".to_string();
+    }
+    let ports = available
+        .iter()
+        .map(|p| format!("'{}'", p))
+        .collect::<Vec<_>>()
+        .join(", ");
+    match suggestion {
+        Some(port) => format!(". Did you mean '{}'? Available ports: {}", port, ports),
+        None => format!(". Available ports: {}", ports),
+    }
+}
+
+// The port containing the closest approximation of `name`, if it's close enough to be a
+// likely typo or a renamed device rather than a different one
+fn closest_port<'a>(name: &str, ports: &'a [String]) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    let limit = (name.chars().count() / 3).max(1);
+    ports
+        .iter()
+        .map(|port| (substring_distance(&name, &port.to_lowercase()), port))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, port)| port.as_str())
+}
+
+// Fewest edits that turn `pattern` into some part of `text`, since device_name only has to
+// be contained in the port name (Levenshtein distance with a free start and end in `text`)
+fn substring_distance(pattern: &str, text: &str) -> usize {
+    let text: Vec<char> = text.chars().collect();
+    let mut row = vec![0; text.len() + 1];
+    for (i, p) in pattern.chars().enumerate() {
+        let mut next = vec![i + 1; text.len() + 1];
+        for (j, &t) in text.iter().enumerate() {
+            next[j + 1] = (row[j] + usize::from(p != t))
+                .min(row[j + 1] + 1)
+                .min(next[j] + 1);
+        }
+        row = next;
+    }
+    row.into_iter().min().unwrap_or(0)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MidiConfig {
+    pub device_name: String,
+    /// How `device_name` is compared with port names
+    #[serde(rename = "match", default)]
+    pub device_match: MatchMode,
+    /// More files whose mappings, layers and sysex entries are merged in, later ones winning
+    #[serde(default)]
+    pub include: Vec<String>,
+    // Keys in TOML are always strings
+    #[serde(default)]
+    pub mappings: HashMap<String, Mapping>,
+    /// Named sets of mappings, switched with `Action::Layer`
+    #[serde(default)]
+    pub layers: HashMap<String, HashMap<String, Mapping>>,
+    /// Fires for any note, CC or program change that no mapping matches
+    pub default: Option<Mapping>,
+    /// One-shot actions for SysEx messages, keyed by their leading bytes in hex (e.g. "F0 00 20 6B")
+    #[serde(default)]
+    pub sysex: HashMap<String, Action>,
+    /// Log a "still listening" line with event counts this often (off when unset)
+    pub heartbeat_secs: Option<u64>,
+    /// Unix socket accepting `reload`, `layer <name>` and `status` commands (off when unset)
+    pub socket: Option<String>,
+    /// Forward incoming MIDI to another port, e.g. for a DAW
+    pub thru: Option<ThruConfig>,
+    /// Keep Toggle states and the active layer in this file across restarts
+    pub state_file: Option<String>,
+    /// How the uinput keyboard presents itself (Linux only)
+    #[serde(default)]
+    pub virtual_device: VirtualDeviceConfig,
+    /// Shell that runs command strings instead of `sh -c` (not on Windows)
+    pub shell: Option<ShellConfig>,
+    /// Friendly control names for the mapping keys: a built-in profile ("mpd218",
+    /// "nanokontrol2") or a profile file. Resolved by `load_config`.
+    pub profile: Option<String>,
+    /// Show desktop notifications for reloads, config errors and the device coming and going
+    #[serde(default)]
+    pub notifications: bool,
+}
+
+impl MidiConfig {
+    /// Check every mapping, returning how many there are across all layers
+    pub fn validate(&self) -> Result<usize> {
+        build_layers(self).map(|layers| layers.len())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ThruConfig {
+    /// Name of the virtual output port to create (on Windows, an existing port to open)
+    pub port: String,
+    /// Don't forward messages for controls that have a mapping
+    #[serde(default)]
+    pub skip_mapped: bool,
+}
+
+/// Response curve applied to a mapping's velocity or value
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(
+    untagged,
+    expecting = "\"linear\", \"exp\", \"log\" or a gamma number such as 1.5"
+)]
+pub enum Curve {
+    Shape(CurveShape),
+    /// `value = max * (value / max) ^ gamma`; above 1 softens light hits, below 1 boosts them
+    Gamma(f64),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveShape {
+    Linear,
+    /// Gamma 2: light hits come out quieter
+    Exp,
+    /// Gamma 0.5: light hits come out louder
+    Log,
+}
+
+impl Curve {
+    fn gamma(self) -> f64 {
+        match self {
+            Curve::Shape(CurveShape::Linear) => 1.0,
+            Curve::Shape(CurveShape::Exp) => 2.0,
+            Curve::Shape(CurveShape::Log) => 0.5,
+            Curve::Gamma(gamma) => gamma,
+        }
+    }
+
+    fn apply(self, input: u16, input_max: u16) -> u16 {
+        let fraction = input.min(input_max) as f64 / input_max as f64;
+        (fraction.powf(self.gamma()) * input_max as f64).round() as u16
+    }
+}
+
+/// A shell to run commands with, e.g. to get the aliases and functions of an interactive zsh
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShellConfig {
+    /// Path of the shell binary, e.g. "/usr/bin/zsh"
+    pub program: String,
+    /// Start it as a login shell (`-l`), reading the profile files
+    #[serde(default)]
+    pub login: bool,
+    /// Start it as an interactive shell (`-i`), reading e.g. ~/.zshrc and its aliases
+    #[serde(default)]
+    pub interactive: bool,
+}
+
+/// Identity of the virtual keyboard, e.g. to tell two daemons apart in compositor input rules
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct VirtualDeviceConfig {
+    /// Device name (default "midi-actions")
+    pub name: Option<String>,
+    /// USB-style vendor and product IDs; 0 when unset
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// The port name must equal `device_name`
+    Exact,
+    /// The port name must contain `device_name`
+    #[default]
+    Contains,
+    /// `device_name` is a regular expression searched for in the port name
+    Regex,
+}
+
+/// Picks the configured device out of the available port names
+#[derive(Clone)]
+pub struct DeviceMatcher {
+    name: String,
+    mode: MatchMode,
+    regex: Option<Regex>,
+}
+
+impl DeviceMatcher {
+    pub fn new(config: &MidiConfig) -> Result<Self> {
+        let regex = match config.device_match {
+            MatchMode::Regex => Some(Regex::new(&config.device_name)?),
+            _ => None,
+        };
+        Ok(Self {
+            name: config.device_name.clone(),
+            mode: config.device_match,
+            regex,
+        })
+    }
+
+    pub fn matches(&self, port_name: &str) -> bool {
+        match (self.mode, &self.regex) {
+            (MatchMode::Exact, _) => port_name == self.name,
+            (MatchMode::Regex, Some(regex)) => regex.is_match(port_name),
+            _ => port_name.contains(&self.name),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Mapping {
+    /// With false the mapping is parsed but otherwise ignored, as if it weren't there (default true)
+    pub enabled: Option<bool>,
+    /// MIDI channel (0-15) this mapping listens on. Matches any channel when omitted.
+    pub channel: Option<u8>,
+    /// Ignore repeat triggers of this control within this many milliseconds (0 = off)
+    #[serde(default)]
+    pub debounce_ms: u64,
+    /// Drop triggers for this many milliseconds after the action fires, however hard it's mashed
+    #[serde(default)]
+    pub cooldown_ms: u64,
+    /// Ignore Note On hits softer than this velocity (default 1, i.e. every hit)
+    pub min_velocity: Option<u8>,
+    /// For buttons that send CC: fire only when the value crosses the midpoint (default: every message)
+    pub trigger: Option<CcTrigger>,
+    /// Reshape the value before the action sees it, e.g. for velocity-sensitive commands
+    pub curve: Option<Curve>,
+    #[serde(flatten)]
+    pub action: Action,
+    /// Fired when the note is released (Note Off or Note On with velocity 0)
+    pub on_release: Option<Action>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Action {
+    /// Taps the key. With `latch` each press instead toggles it between held down and
+    /// released, for footswitches that only send momentary presses.
+    Key {
+        code: String,
+        #[serde(default)]
+        latch: bool,
+    },
+    /// Held down while the note is held: press on NOTE_ON, release on NOTE_OFF. With `latch`
+    /// it behaves like a latching Key.
+    KeyHold {
+        code: String,
+        #[serde(default)]
+        latch: bool,
+    },
+    /// Taps the key on NOTE_ON and, after `initial_delay_ms`, every `rate_ms` until NOTE_OFF
+    KeyRepeat {
+        code: String,
+        rate_ms: Option<u64>,
+        initial_delay_ms: Option<u64>,
+        /// Repeat interval at full aftertouch; the pad's pressure moves it between `rate_ms` and this
+        pressure_rate_ms: Option<u64>,
+    },
+    /// Presses all keys together (e.g. Ctrl+Shift+S), then releases them in reverse order
+    Chord { codes: Vec<String> },
+    Command {
+        cmd: String,
+        /// Kill the command if it is still running after this many milliseconds
+        timeout_ms: Option<u64>,
+        /// Working directory for the command (default: the daemon's)
+        cwd: Option<String>,
+        /// Extra environment variables for the command
+        #[serde(default)]
+        env: HashMap<String, String>,
+        /// Skip the trigger while this control's previous command is still queued or running
+        #[serde(default)]
+        singleton: bool,
+        /// Shell command run when `cmd` exits non-zero, with `{exit_code}` substituted
+        on_error: Option<String>,
+    },
+    /// Runs `program` directly with `args`, without a shell to split or expand them
+    Exec {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        timeout_ms: Option<u64>,
+        cwd: Option<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+        #[serde(default)]
+        singleton: bool,
+        on_error: Option<String>,
+    },
+    /// `{}` in the template is replaced by the value scaled into `min..=max` (default 0-100)
+    Linear {
+        template: String,
+        min: Option<f64>,
+        max: Option<f64>,
+        #[serde(default)]
+        invert: bool,
+        /// Substitute a number with this many decimals instead of a whole number
+        precision: Option<usize>,
+        /// Only run when the scaled value moved at least this far from the last one sent (default 1)
+        threshold: Option<i64>,
+        /// With false, run on every message even when the value didn't change (default true)
+        dedup: Option<bool>,
+        /// Value at the middle of the control's travel, e.g. 0 for a pan knob scaled to -100..100.
+        /// Each half is then scaled separately, so the midpoint gives exactly this value.
+        center: Option<f64>,
+        /// Combine this CC with a second one carrying the low 7 bits into a 0-16383 value
+        high_res: Option<HighRes>,
+        /// After the control was last used in another layer, wait until it reaches this
+        /// layer's last position before sending anything, so the value doesn't jump
+        #[serde(default)]
+        soft_takeover: bool,
+        /// Smooth out jitter with a moving average before scaling: each message moves the
+        /// value only `1 - smoothing` of the way to the new reading (0 is off, up to below 1)
+        smoothing: Option<f64>,
+    },
+    /// Splits the control's range into one equal band per command and runs a band's
+    /// command when the control moves into it
+    Steps { commands: Vec<String> },
+    /// Mapped on a range of ids (e.g. "20-27"): runs one `template` with every member's
+    /// latest value as `{v0}`, `{v1}`, ... (lowest id first) or `{values}`, at most once per
+    /// `settle_ms` while any of them moves
+    FaderGroup {
+        template: String,
+        settle_ms: Option<u64>,
+        /// The ids of the mapping key, filled in when the config is loaded
+        #[serde(skip)]
+        members: Vec<Control>,
+    },
+    /// Runs `inc_cmd` or `dec_cmd` depending on which way an encoder turned.
+    /// `{delta}` in either command is replaced by the size of the step.
+    /// With `cmd` instead, one command gets a signed `{delta}` (negative counter-clockwise).
+    Relative {
+        inc_cmd: Option<String>,
+        dec_cmd: Option<String>,
+        cmd: Option<String>,
+        mode: Option<RelativeMode>,
+        /// Run the command once per unit of the step (up to `MAX_RELATIVE_REPEAT`) instead of once
+        #[serde(default)]
+        repeat: bool,
+        acceleration: Option<Acceleration>,
+        timeout_ms: Option<u64>,
+    },
+    /// Alternates between `on_cmd` and `off_cmd` on each press, starting from `initial` (off).
+    /// In momentary mode `on_cmd` runs on press and `off_cmd` on release instead.
+    Toggle {
+        on_cmd: String,
+        off_cmd: String,
+        #[serde(default)]
+        initial: bool,
+        #[serde(default)]
+        mode: ToggleMode,
+        timeout_ms: Option<u64>,
+        feedback: Option<Feedback>,
+    },
+    /// Runs `cmd` with `{bpm}` set from the average interval between the last `taps`
+    /// presses (default 4). A gap longer than `reset_ms` (default 2000) starts over.
+    TapTempo {
+        cmd: String,
+        taps: Option<usize>,
+        reset_ms: Option<u64>,
+        timeout_ms: Option<u64>,
+    },
+    /// Makes the named layer active ("default" returns to the base mappings)
+    Layer { name: String },
+    /// Makes `layer` active only while the pad is held, like a Shift key
+    Modifier { layer: String },
+    /// Scrolls `amount` per press, or per step a knob turns (positive is down/right)
+    Scroll {
+        axis: Axis,
+        amount: i32,
+        mode: Option<RelativeMode>,
+    },
+    /// Moves the pointer `sensitivity` pixels per press, or per step a knob turns
+    MouseMove {
+        axis: Axis,
+        sensitivity: i32,
+        mode: Option<RelativeMode>,
+    },
+    /// Sets a sink's volume from a knob or fader, scaled into 0-`max` percent (default 100)
+    Volume {
+        /// PulseAudio/PipeWire sink name (default "@DEFAULT_SINK@"; ignored on macOS)
+        sink: Option<String>,
+        max: Option<u32>,
+    },
+    /// Sends an HTTP request from a command worker; `{value}`, `{id}` and `{channel}`
+    /// are substituted in the URL and body
+    Http {
+        url: String,
+        /// Defaults to POST
+        method: Option<String>,
+        body: Option<String>,
+        /// Give up on the request after this many milliseconds (default 5000)
+        timeout_ms: Option<u64>,
+    },
+    /// Sends an OSC message over UDP to `address` (host:port)
+    Osc {
+        address: String,
+        path: String,
+        #[serde(default)]
+        args: Vec<OscArg>,
+    },
+    /// Fires several actions for the same control, in order
+    Multi { actions: Vec<Action> },
+    /// Runs Key, Chord, Command, Exec, Scroll and MouseMove steps in order on their own thread,
+    /// waiting `delay_ms` between them
+    Sequence {
+        steps: Vec<Action>,
+        delay_ms: Option<u64>,
+    },
+    /// Runs `check_cmd` on its own thread and fires `then` if it exits with 0, otherwise `else`.
+    /// Both take the same action types as Sequence steps.
+    Conditional {
+        check_cmd: String,
+        then: Box<Action>,
+        #[serde(rename = "else")]
+        otherwise: Option<Box<Action>>,
+    },
+    /// Fires `short` if the pad is released within `threshold_ms` (default 500),
+    /// otherwise `long` once the threshold passes while it is still held
+    Press {
+        short: Box<Action>,
+        long: Box<Action>,
+        threshold_ms: Option<u64>,
+    },
+    /// Fires `action` on every `ticks`-th MIDI clock pulse (24 per quarter note), counting
+    /// from the last Start or Stop. Only the "clock" key can drive it.
+    ClockDivider { ticks: u32, action: Box<Action> },
+    /// Fires the action of the highest band whose velocity threshold the hit reaches, e.g.
+    /// `bands = [[1, soft], [100, hard]]`. Takes the same action types as Sequence steps.
+    VelocityBands { bands: Vec<(u8, Action)> },
+    /// Asks the daemon to shut down as it would on SIGTERM: held keys are released and
+    /// running commands get their grace period
+    Quit,
+}
+
+/// A typed OSC argument. The text is a template: besides the usual placeholders,
+/// `{normalized}` is the control's value scaled to 0.0-1.0.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum OscArg {
+    Int(String),
+    Float(String),
+    String(String),
+}
+
+/// Which crossings of the CC midpoint (64) fire a mapping
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CcTrigger {
+    /// Low to high, i.e. the button was pressed
+    Rising,
+    /// High to low, i.e. the button was released
+    Falling,
+    /// Either way
+    Change,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ToggleMode {
+    /// Each press flips the state
+    #[default]
+    Latch,
+    /// On while the pad is held down
+    Momentary,
+}
+
+/// Note On sent back to the device when a Toggle flips, e.g. to set a pad's LED colour
+#[derive(Deserialize, Debug, Clone)]
+pub struct Feedback {
+    /// Note to send (defaults to the mapping's id)
+    pub note: Option<u8>,
+    /// Velocity (colour on most controllers) for the on state
+    pub on: u8,
+    #[serde(default)]
+    pub off: u8,
+}
+
+/// Makes a Relative encoder step further the faster it turns, like pointer acceleration
+#[derive(Deserialize, Debug, Clone)]
+pub struct Acceleration {
+    /// Largest multiplier, reached when steps arrive back to back
+    pub factor: f64,
+    /// Steps further apart than this many milliseconds aren't accelerated (default 50)
+    pub window_ms: Option<u64>,
+}
+
+/// The two CCs of a 14-bit control; by convention the LSB is the MSB + 32
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HighRes {
+    /// CC with the high 7 bits, which must be the mapping's id
+    pub msb: u8,
+    /// CC with the low 7 bits
+    pub lsb: u8,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    #[serde(alias = "x")]
+    Horizontal,
+    #[serde(alias = "y")]
+    Vertical,
+}
+
+#[cfg(target_os = "linux")]
+impl Axis {
+    fn wheel(self) -> RelativeAxisType {
+        match self {
+            Axis::Horizontal => RelativeAxisType::REL_HWHEEL,
+            Axis::Vertical => RelativeAxisType::REL_WHEEL,
+        }
+    }
+
+    fn pointer(self) -> RelativeAxisType {
+        match self {
+            Axis::Horizontal => RelativeAxisType::REL_X,
+            Axis::Vertical => RelativeAxisType::REL_Y,
+        }
+    }
+}
+
+/// How a relative (endless) encoder encodes its steps in the CC value.
+/// Without a mode the value is treated as an absolute position and compared to the previous one.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RelativeMode {
+    /// 1..=63 clockwise, 127 (-1) down to 64 counter-clockwise
+    TwosComplement,
+    /// Bit 6 is the sign (set = counter-clockwise), the low 6 bits the magnitude
+    SignedBit,
+    /// 64 is the zero point: 65 = +1, 63 = -1
+    BinaryOffset,
+}
+
+impl Action {
+    // Every key code this action can emit
+    fn key_codes(&self) -> Vec<&str> {
+        match self {
+            Action::Key { code, .. }
+            | Action::KeyHold { code, .. }
+            | Action::KeyRepeat { code, .. } => {
+                vec![code.as_str()]
+            }
+            Action::Chord { codes } => codes.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // The action itself followed by any Multi actions, Sequence steps and Conditional or Press
+    // branches inside it
+    fn with_nested(&self) -> Vec<&Action> {
+        let mut all = vec![self];
+        match self {
+            Action::Multi { actions } => all.extend(actions.iter().flat_map(Action::with_nested)),
+            Action::Sequence { steps, .. } => all.extend(steps),
+            Action::Conditional {
+                then, otherwise, ..
+            } => all.extend([then].into_iter().chain(otherwise).map(Box::as_ref)),
+            Action::Press { short, long, .. } => all.extend([short.as_ref(), long.as_ref()]),
+            Action::ClockDivider { action, .. } => all.push(action),
+            Action::VelocityBands { bands } => all.extend(bands.iter().map(|(_, action)| action)),
+            _ => {}
+        }
+        all
+    }
+}
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const POLY_PRESSURE: u8 = 0xA0;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const CHANNEL_PRESSURE: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+const SYSEX_START: u8 = 0xF0;
+const CLOCK_TICK: u8 = 0xF8;
+const CLOCK_START: u8 = 0xFA;
+const CLOCK_CONTINUE: u8 = 0xFB;
+const CLOCK_STOP: u8 = 0xFC;
+
+// Name of the uinput keyboard unless `virtual_device.name` says otherwise
+#[cfg(target_os = "linux")]
+const VIRTUAL_DEVICE_NAME: &str = "midi-actions";
+
+// Worker threads that start commands and send Http requests
+const COMMAND_WORKERS: usize = 2;
+// Commands queued beyond this are dropped rather than piling up behind slow workers
+const MAX_QUEUED_COMMANDS: usize = 64;
+// `{exit_code}` for an on_error command when the program couldn't be started at all,
+// as a shell reports for a program it can't find
+const SPAWN_FAILED_EXIT_CODE: i32 = 127;
+// How long shutdown waits for running commands before leaving them behind
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+// Cap on how many times a fast `repeat` spin runs its command, to keep the queue usable
+const MAX_RELATIVE_REPEAT: u32 = 10;
+// Gap between encoder steps below which an `acceleration` starts to apply
+const DEFAULT_ACCELERATION_WINDOW_MS: u64 = 50;
+// How often the reaper checks whether running commands have exited or timed out
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+// TapTempo defaults: intervals averaged, and the gap that starts a new measurement
+const DEFAULT_TAPS: usize = 4;
+const DEFAULT_TAP_RESET_MS: u64 = 2000;
+// How long a Press pad must be held before it counts as a long press
+const DEFAULT_LONG_PRESS_MS: u64 = 500;
+// How long a FaderGroup collects moves before running its command
+const DEFAULT_GROUP_SETTLE_MS: u64 = 50;
+// How long a smoothed Linear must be still before it jumps to its actual reading
+const SMOOTHING_SETTLE: Duration = Duration::from_millis(100);
+// An average trailing the reading by more than this fraction of the range is a real move,
+// not jitter
+const SMOOTHING_DEADBAND: f64 = 1.0 / 32.0;
+// KeyRepeat defaults, close to a typical keyboard's autorepeat
+const DEFAULT_KEY_REPEAT_MS: u64 = 50;
+const DEFAULT_KEY_REPEAT_DELAY_MS: u64 = 300;
+// Http actions that don't set timeout_ms give up after this long
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PITCH_BEND_MAX: u16 = 16383;
+
+/// What a mapping listens to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    /// Note, CC or program number - they share one id space
+    Id(u8),
+    /// The channel's pitch wheel (14-bit)
+    PitchBend,
+    /// Channel pressure (aftertouch) across all held pads
+    Pressure,
+    /// Polyphonic pressure of a single note
+    NotePressure(u8),
+    /// A SysEx message, matched by its leading bytes rather than an id
+    SysEx,
+    /// MIDI clock pulses, for ClockDivider actions
+    Clock,
+}
+
+impl std::str::FromStr for Control {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pitchbend" => Ok(Control::PitchBend),
+            "pressure" => Ok(Control::Pressure),
+            "clock" => Ok(Control::Clock),
+            _ => match s.strip_prefix("pressure:") {
+                Some(note) => note.parse::<u8>().map(Control::NotePressure),
+                None => s.parse::<u8>().map(Control::Id),
+            }
+            .map_err(|_| {
+                "id must be a number from 0 to 255, \"pitchbend\", \"pressure\", \"pressure:<note>\" or \"clock\""
+                    .to_string()
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Control::Id(id) => write!(f, "{}", id),
+            Control::PitchBend => write!(f, "pitchbend"),
+            Control::Pressure => write!(f, "pressure"),
+            Control::NotePressure(note) => write!(f, "pressure:{}", note),
+            Control::SysEx => write!(f, "sysex"),
+            Control::Clock => write!(f, "clock"),
+        }
+    }
+}
+
+impl Control {
+    /// The message a controller would send for this control with `value`: a Note On (or a
+    /// Control Change with `cc`) for ids. A Note On with value 0 is a release, and `clock`
+    /// gives a single clock pulse.
+    pub fn message(self, channel: u8, value: u16, cc: bool) -> Result<Vec<u8>> {
+        let invalid = || MidiActionError::InvalidTrigger {
+            control: self,
+            value,
+        };
+        let max = if self == Control::PitchBend {
+            PITCH_BEND_MAX
+        } else {
+            127
+        };
+        if value > max {
+            return Err(invalid());
+        }
+        let channel = channel & 0x0f;
+        let data = value as u8;
+        Ok(match self {
+            Control::Id(id) if id <= 127 && cc => vec![CONTROL_CHANGE | channel, id, data],
+            Control::Id(id) if id <= 127 => vec![NOTE_ON | channel, id, data],
+            Control::PitchBend => vec![
+                PITCH_BEND | channel,
+                (value & 0x7f) as u8,
+                (value >> 7) as u8,
+            ],
+            Control::Pressure => vec![CHANNEL_PRESSURE | channel, data],
+            Control::NotePressure(note) if note <= 127 => {
+                vec![POLY_PRESSURE | channel, note, data]
+            }
+            Control::Clock => vec![CLOCK_TICK],
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// A channel message decoded from raw MIDI bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiEvent {
+    /// Also produced by a Note On with velocity 0, which many devices send instead
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// 14-bit position, 8192 at rest
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+}
+
+impl MidiEvent {
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiEvent::NoteOff { channel, .. }
+            | MidiEvent::NoteOn { channel, .. }
+            | MidiEvent::PolyPressure { channel, .. }
+            | MidiEvent::ControlChange { channel, .. }
+            | MidiEvent::ProgramChange { channel, .. }
+            | MidiEvent::ChannelPressure { channel, .. }
+            | MidiEvent::PitchBend { channel, .. } => channel,
+        }
+    }
+
+    // The control mappings are looked up by
+    pub fn control(&self) -> Control {
+        match *self {
+            MidiEvent::NoteOff { note, .. } | MidiEvent::NoteOn { note, .. } => Control::Id(note),
+            MidiEvent::PolyPressure { note, .. } => Control::NotePressure(note),
+            MidiEvent::ControlChange { controller, .. } => Control::Id(controller),
+            MidiEvent::ProgramChange { program, .. } => Control::Id(program),
+            MidiEvent::ChannelPressure { .. } => Control::Pressure,
+            MidiEvent::PitchBend { .. } => Control::PitchBend,
+        }
+    }
+
+    // The 7-bit value, then the full-resolution value and its maximum for Linear scaling,
+    // which only differ for the 14-bit pitch wheel
+    pub fn value(&self) -> (u8, u16, u16) {
+        let value = match *self {
+            MidiEvent::NoteOff { velocity, .. } | MidiEvent::NoteOn { velocity, .. } => velocity,
+            MidiEvent::PolyPressure { pressure, .. }
+            | MidiEvent::ChannelPressure { pressure, .. } => pressure,
+            MidiEvent::ControlChange { value, .. } => value,
+            // Program Change has no value byte; the program number doubles as the value
+            MidiEvent::ProgramChange { program, .. } => program,
+            MidiEvent::PitchBend { value, .. } => {
+                return ((value >> 7) as u8, value, PITCH_BEND_MAX)
+            }
+        };
+        (value, value as u16, 127)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MidiEvent::NoteOff { .. } => "Note Off",
+            MidiEvent::NoteOn { .. } => "Note On",
+            MidiEvent::PolyPressure { .. } => "Poly Pressure",
+            MidiEvent::ControlChange { .. } => "Control Change",
+            MidiEvent::ProgramChange { .. } => "Program Change",
+            MidiEvent::ChannelPressure { .. } => "Channel Pressure",
+            MidiEvent::PitchBend { .. } => "Pitch Bend",
+        }
+    }
+}
+
+// Decode one incoming message. System messages (clock, SysEx, ...) and messages too short
+// for their type give None.
+pub fn decode(msg: &[u8]) -> Option<MidiEvent> {
+    let (&status, data) = msg.split_first()?;
+    let channel = status & 0x0f;
+    Some(match (status & 0xf0, data) {
+        (NOTE_OFF, &[note, velocity, ..]) => MidiEvent::NoteOff {
+            channel,
+            note,
+            velocity,
+        },
+        (NOTE_ON, &[note, 0, ..]) => MidiEvent::NoteOff {
+            channel,
+            note,
+            velocity: 0,
+        },
+        (NOTE_ON, &[note, velocity, ..]) => MidiEvent::NoteOn {
+            channel,
+            note,
+            velocity,
+        },
+        (POLY_PRESSURE, &[note, pressure, ..]) => MidiEvent::PolyPressure {
+            channel,
+            note,
+            pressure,
+        },
+        (CONTROL_CHANGE, &[controller, value, ..]) => MidiEvent::ControlChange {
+            channel,
+            controller,
+            value,
+        },
+        (PROGRAM_CHANGE, &[program, ..]) => MidiEvent::ProgramChange { channel, program },
+        (CHANNEL_PRESSURE, &[pressure, ..]) => MidiEvent::ChannelPressure { channel, pressure },
+        (PITCH_BEND, &[lsb, msb, ..]) => MidiEvent::PitchBend {
+            channel,
+            value: ((msb as u16) << 7) | lsb as u16,
+        },
+        // System messages (SysEx, clock, ...) have no channel and are handled separately
+        _ => return None,
+    })
+}
+
+// (channel, control) - a `None` channel matches any channel
+type MappingKey = (Option<u8>, Control);
+type Mappings = HashMap<MappingKey, Mapping>;
+
+// On/off state of Toggle actions, per (channel, control, action index)
+type ToggleStates = HashMap<(u8, Control, usize), bool>;
+
+// Key codes held down by KeyHold or latching actions, per (channel, control) that pressed them
+type HeldKeys = HashMap<(u8, Control), Vec<String>>;
+
+// Name of the implicit layer made of the top-level `mappings`
+const DEFAULT_LAYER: &str = "default";
+
+// Soft takeover bookkeeping for Linear controls
+#[derive(Default)]
+struct Pickup {
+    // Each control's last position and the layer whose mapping handled it
+    positions: HashMap<ActionKey, (u16, String)>,
+    // Position each layer's Linear was last driven to, and whether the control drives it again
+    values: HashMap<(String, ActionKey), (u16, bool)>,
+}
+
+impl Pickup {
+    // Record a move to `input` under `layer`, returning whether it may change the value
+    fn engaged(&mut self, layer: &str, key: ActionKey, input: u16) -> bool {
+        let previous = self.positions.insert(key, (input, layer.to_string()));
+        let entry = self.values.get_mut(&(layer.to_string(), key));
+        let (Some((value, engaged)), Some((position, last_layer))) = (entry, previous) else {
+            // Nothing was set through this layer yet, so there's no value to jump from
+            self.values.insert((layer.to_string(), key), (input, true));
+            return true;
+        };
+        // Used in another layer since: engage only once this move reaches or passes the value
+        if last_layer != layer || !*engaged {
+            *engaged = position.min(input) <= *value && *value <= position.max(input);
+        }
+        if *engaged {
+            *value = input;
+        }
+        *engaged
+    }
+}
+
+/// The base mappings plus every named layer
+struct Layers {
+    base: Mappings,
+    named: HashMap<String, Mappings>,
+    fallback: Option<Mapping>,
+    // SysEx prefixes and their actions, longest prefix first
+    sysex: Vec<(Vec<u8>, Action)>,
+    // Every 14-bit CC pair mapped in any layer
+    high_res: Vec<HighRes>,
+}
+
+impl Layers {
+    fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.base
+            .values()
+            .chain(self.named.values().flat_map(|m| m.values()))
+            .chain(&self.fallback)
+            .flat_map(|m| std::iter::once(&m.action).chain(&m.on_release))
+            .chain(self.sysex.iter().map(|(_, action)| action))
+    }
+
+    fn len(&self) -> usize {
+        self.base.len() + self.named.values().map(HashMap::len).sum::<usize>() + self.sysex.len()
+    }
+
+    fn has_layer(&self, name: &str) -> bool {
+        name == DEFAULT_LAYER || self.named.contains_key(name)
+    }
+
+    // The active layer's mapping wins; controls it doesn't map fall through to the base layer,
+    // then to the `default` action
+    fn lookup(&self, active: &str, channel: u8, id: Control) -> Option<&Mapping> {
+        self.lookup_in(active, channel, id)
+            .map(|(_, mapping)| mapping)
+    }
+
+    // Like `lookup`, also naming the layer the mapping belongs to (the fallback counts as default)
+    fn lookup_in(&self, active: &str, channel: u8, id: Control) -> Option<(&str, &Mapping)> {
+        self.named
+            .get_key_value(active)
+            .and_then(|(name, layer)| Some((name.as_str(), lookup_mapping(layer, channel, id)?)))
+            .or_else(|| lookup_mapping(&self.base, channel, id).map(|m| (DEFAULT_LAYER, m)))
+            // Pitch bend and pressure stream continuously, so they only fire what maps them
+            .or(self
+                .fallback
+                .as_ref()
+                .filter(|m| matches!(id, Control::Id(_)) && m.channel.is_none_or(|c| c == channel))
+                .map(|m| (DEFAULT_LAYER, m)))
+    }
+
+    // The pair `cc` belongs to, if the mapping for its MSB in `active` is a 14-bit Linear
+    fn high_res_pair(&self, active: &str, channel: u8, cc: u8) -> Option<HighRes> {
+        self.high_res
+            .iter()
+            .filter(|pair| pair.msb == cc || pair.lsb == cc)
+            .find_map(
+                |pair| match self.lookup(active, channel, Control::Id(pair.msb))?.action {
+                    Action::Linear {
+                        high_res: Some(mapped),
+                        ..
+                    } if mapped == *pair => Some(mapped),
+                    _ => None,
+                },
+            )
+    }
+
+    // The most specific SysEx pattern the message starts with
+    fn lookup_sysex(&self, msg: &[u8]) -> Option<&Action> {
+        self.sysex
+            .iter()
+            .find(|(prefix, _)| msg.starts_with(prefix))
+            .map(|(_, action)| action)
+    }
+}
+
+// The layer mappings are looked up in: the most recently held Modifier's, else the active one
+fn effective_layer<'a>(active: &'a str, held_modifiers: &'a [(ControlKey, String)]) -> &'a str {
+    held_modifiers
+        .last()
+        .map_or(active, |(_, layer)| layer.as_str())
+}
+
+// Print a decoded event with a mapping for it that can be pasted into the config, as shown
+// by setup mode and --learn. Releases are skipped.
+pub fn print_suggestion(msg: &[u8], event: &MidiEvent) {
+    let channel = event.channel();
+    let (found, mapping) = match *event {
+        MidiEvent::ControlChange { controller, .. } => (
+            format!("Knob Detected (ID: {}, Channel: {})", controller, channel),
+            format!(
+                "\"{}\" = {{ type = \"Linear\", channel = {}, template = \"pactl set-sink-volume @DEFAULT_SINK@ {{}}%\" }}",
+                controller, channel
+            ),
+        ),
+        MidiEvent::PolyPressure { note, pressure, .. } => (
+            format!(
+                "Note Pressure Detected (Note: {}, Value: {}, Channel: {})",
+                note, pressure, channel
+            ),
+            format!(
+                "\"pressure:{}\" = {{ type = \"Linear\", channel = {}, template = \"echo {{}}\" }}",
+                note, channel
+            ),
+        ),
+        MidiEvent::PitchBend { value, .. } => (
+            format!("Pitch Bend Detected (Value: {}, Channel: {})", value, channel),
+            format!(
+                "\"pitchbend\" = {{ type = \"Linear\", channel = {}, template = \"echo {{}}\" }}",
+                channel
+            ),
+        ),
+        MidiEvent::ChannelPressure { pressure, .. } => (
+            format!(
+                "Channel Pressure Detected (Value: {}, Channel: {})",
+                pressure, channel
+            ),
+            format!(
+                "\"pressure\" = {{ type = \"Linear\", channel = {}, template = \"echo {{}}\" }}",
+                channel
+            ),
+        ),
+        MidiEvent::NoteOn { note, .. } => (
+            format!("Button Detected (ID: {}, Channel: {})", note, channel),
+            key_suggestion(note, channel),
+        ),
+        MidiEvent::ProgramChange { program, .. } => (
+            format!("Program Change Detected (ID: {}, Channel: {})", program, channel),
+            key_suggestion(program, channel),
+        ),
+        MidiEvent::NoteOff { .. } => return,
+    };
+    let raw: Vec<String> = msg.iter().map(u8::to_string).collect();
+    println!(
+        "RAW: [{}] -> Type: {:#x}, Channel: {}",
+        raw.join(", "),
+        msg[0] & 0xf0,
+        channel
+    );
+    println!("# {}", found);
+    println!("{}\n", mapping);
+}
+
+// Like `print_suggestion`, for a SysEx message
+pub fn print_sysex_suggestion(msg: &[u8]) {
+    let bytes = sysex_hex(msg);
+    println!("RAW: [{}] -> Type: SysEx", bytes);
+    println!(
+        "# SysEx Detected ({} bytes), map it under [sysex]",
+        msg.len()
+    );
+    println!("\"{}\" = {{ type = \"Key\", code = \"KEY_F13\" }}\n", bytes);
+}
+
+/// A SysEx message as the space-separated hex bytes `[sysex]` keys are written in
+pub fn sysex_hex(msg: &[u8]) -> String {
+    msg.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn key_suggestion(id: u8, channel: u8) -> String {
+    format!(
+        "\"{}\" = {{ type = \"Key\", channel = {}, code = \"KEY_F13\" }}",
+        id, channel
+    )
+}
+
+// --- ENGINE ---
+
+/// Where the engine reads the time from for debounce, cooldown, long presses, tap tempo,
+/// encoder acceleration and settling smoothed faders. `SystemClock` in production; tests use one they can move forward.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Run `task` once `delay` has passed, by default on a thread of its own
+    fn after(&self, delay: Duration, task: Box<dyn FnOnce() + Send>) {
+        thread::spawn(move || {
+            thread::sleep(delay);
+            task();
+        });
+    }
+}
+
+/// The real time, from `Instant::now()`
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+type ControlKey = (u8, Control);
+type ActionKey = (u8, Control, usize);
+
+/// Turns incoming MIDI messages into actions. All state lives behind locks, so an `Engine`
+/// can be shared (e.g. in an `Arc`) between the MIDI callback and other threads.
+pub struct Engine {
+    mappings: RwLock<Layers>,
+    // Sequences, Conditionals and long presses run on their own threads and take the keyboard
+    // for each step
+    keyboard: Arc<Mutex<VirtualKeyboard>>,
+    commands: CommandQueue,
+    device: DeviceMatcher,
+    dry_run: bool,
+    // Print controls that have no mapping, like setup mode
+    learn: bool,
+    volume: VolumeControl,
+    feedback: Mutex<FeedbackOutput>,
+    thru: Option<Mutex<ThruOutput>>,
+    osc: Mutex<OscSender>,
+    state_file: StateFile,
+    // Name of the layer whose mappings currently take priority
+    active_layer: Mutex<String>,
+    // Layers of the Modifier pads currently held, in the order they were pressed. The last one
+    // overrides `active_layer` until it is released.
+    held_modifiers: Mutex<Vec<(ControlKey, String)>>,
+    // Current on/off state of Toggle controls
+    toggle_states: Mutex<ToggleStates>,
+    last_knob_vals: Arc<Mutex<HashMap<ActionKey, i64>>>,
+    // Last text sent by Linear actions with a precision
+    last_linear_texts: Arc<Mutex<HashMap<ActionKey, String>>>,
+    // Moving average of the input of Linear actions with smoothing
+    smoothed_vals: Arc<Mutex<HashMap<ActionKey, Smoothed>>>,
+    // Where soft takeover Linear controls are in each layer
+    pickup: Arc<Mutex<Pickup>>,
+    // Previous absolute position of Relative/Scroll/MouseMove controls without a decoding mode
+    last_relative_vals: Mutex<HashMap<ActionKey, u8>>,
+    // When each Relative control with an acceleration last stepped
+    last_steps: Mutex<HashMap<ActionKey, Instant>>,
+    // When each debounced control last sent a trigger
+    last_triggered: Mutex<HashMap<ControlKey, Instant>>,
+    // When each control with a cooldown last fired
+    last_fired: Mutex<HashMap<ControlKey, Instant>>,
+    // Last MSB of each 14-bit CC pair, for the LSBs that refine it
+    cc_msb: Mutex<HashMap<ControlKey, u8>>,
+    // Whether each CC with a `trigger` was last above the midpoint
+    cc_high: Mutex<HashMap<ControlKey, bool>>,
+    // Notes whose hit was below min_velocity, so their release is ignored too
+    ghost_notes: Mutex<HashSet<ControlKey>>,
+    // When each Press pad still waiting to become a long press went down
+    pending_presses: Arc<Mutex<HashMap<ActionKey, Instant>>>,
+    // Recent press times of TapTempo controls, oldest first
+    tap_times: Mutex<HashMap<ActionKey, VecDeque<Instant>>>,
+    // Key codes currently held down by KeyHold mappings
+    held_keys: Mutex<HeldKeys>,
+    // Key codes latched down by `latch = true` mappings until their next press
+    latched_keys: Mutex<HeldKeys>,
+    // Latest member values of each FaderGroup, keyed by its lowest id
+    group_states: Arc<Mutex<HashMap<ActionKey, GroupState>>>,
+    // Clock pulses each ClockDivider has counted since it last fired, per action index
+    clock_ticks: Mutex<HashMap<ActionKey, u32>>,
+    // When each KeyRepeat pad currently repeating went down, and its latest aftertouch; its
+    // thread stops once the entry is gone or replaced
+    repeating_keys: Arc<Mutex<HashMap<ActionKey, (Instant, u8)>>>,
+    // MIDI messages received (in total and by type) and actions fired, for the heartbeat
+    // and status
+    events: AtomicU64,
+    event_counts: Mutex<HashMap<&'static str, u64>>,
+    fired: AtomicU64,
+    // Set by a Quit action, for whoever runs the engine to stop it
+    quit: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Engine {
+    /// Validate `config` and set up everything its actions need. With `dry_run` the actions
+    /// are only logged; with `learn` unmapped controls are printed with a suggested mapping.
+    pub fn new(config: &MidiConfig, dry_run: bool, learn: bool) -> Result<Self> {
+        Self::with_clock(config, dry_run, learn, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, reading the time from `clock`
+    pub fn with_clock(
+        config: &MidiConfig,
+        dry_run: bool,
+        learn: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let layers = build_layers(config)?;
+        let mut osc = OscSender::new(dry_run);
+        osc.resolve(layers.actions());
+        let keyboard = VirtualKeyboard::new(layers.actions(), &config.virtual_device, dry_run)?;
+        let device = DeviceMatcher::new(config)?;
+        let mut feedback = FeedbackOutput::connect(&device, dry_run);
+        let thru = match &config.thru {
+            Some(_) if dry_run => {
+                info!("[dry-run] Not forwarding MIDI to a thru port");
+                None
+            }
+            Some(thru) => Some(Mutex::new(ThruOutput::open(thru)?)),
+            None => None,
+        };
+        let state_file = StateFile {
+            path: config
+                .state_file
+                .as_deref()
+                .map(|path| PathBuf::from(expand_env(path))),
+            dry_run,
+        };
+        let (saved_layer, saved_toggles) = state_file.load();
+        let active_layer = match saved_layer {
+            Some(layer) if layers.has_layer(&layer) => {
+                info!("Restored layer '{}'", layer);
+                layer
+            }
+            _ => DEFAULT_LAYER.to_string(),
+        };
+        send_toggle_feedback(&layers, &active_layer, &saved_toggles, &mut feedback);
+
+        Ok(Self {
+            mappings: RwLock::new(layers),
+            keyboard: Arc::new(Mutex::new(keyboard)),
+            commands: CommandQueue::new(COMMAND_WORKERS, dry_run, config.shell.clone()),
+            device,
+            dry_run,
+            learn,
+            volume: VolumeControl::new(dry_run),
+            feedback: Mutex::new(feedback),
+            thru,
+            osc: Mutex::new(osc),
+            state_file,
+            active_layer: Mutex::new(active_layer),
+            held_modifiers: Mutex::default(),
+            toggle_states: Mutex::new(saved_toggles),
+            last_knob_vals: Arc::default(),
+            last_linear_texts: Arc::default(),
+            smoothed_vals: Arc::default(),
+            pickup: Arc::default(),
+            last_relative_vals: Mutex::default(),
+            last_steps: Mutex::default(),
+            last_triggered: Mutex::default(),
+            last_fired: Mutex::default(),
+            cc_msb: Mutex::default(),
+            cc_high: Mutex::default(),
+            ghost_notes: Mutex::default(),
+            pending_presses: Arc::default(),
+            tap_times: Mutex::default(),
+            clock_ticks: Mutex::default(),
+            group_states: Arc::default(),
+            held_keys: Mutex::default(),
+            latched_keys: Mutex::default(),
+            repeating_keys: Arc::default(),
+            events: AtomicU64::new(0),
+            event_counts: Mutex::default(),
+            fired: AtomicU64::new(0),
+            quit: AtomicBool::new(false),
+            clock,
+        })
+    }
+
+    /// Swap in the mappings from `config`. On error the current mappings stay in place.
+    pub fn reload(&self, config: &MidiConfig) -> Result<()> {
+        let new_mappings = build_layers(config)?;
+        if let Err(e) = self
+            .keyboard
+            .lock()
+            .unwrap()
+            .ensure_keys(new_mappings.actions())
+        {
+            error!("Failed to rebuild virtual keyboard: {}", e);
+        }
+        // Looked up again, in case a host has moved
+        let mut osc = self.osc.lock().unwrap();
+        osc.targets.clear();
+        osc.resolve(new_mappings.actions());
+        drop(osc);
+        *self.mappings.write().unwrap() = new_mappings;
+        Ok(())
+    }
+
+    /// The device the config asks for
+    pub fn device(&self) -> &DeviceMatcher {
+        &self.device
+    }
+
+    /// Number of mappings across all layers
+    pub fn mapping_count(&self) -> usize {
+        self.mappings.read().unwrap().len()
+    }
+
+    pub fn active_layer(&self) -> String {
+        self.active_layer.lock().unwrap().clone()
+    }
+
+    /// Make `name` the active layer, as a Layer action would
+    pub fn set_layer(&self, name: &str) -> Result<()> {
+        if !self.mappings.read().unwrap().has_layer(name) {
+            return Err(MidiActionError::UnknownLayer(name.to_string()));
+        }
+        let mut active_layer = self.active_layer.lock().unwrap();
+        if *active_layer != name {
+            info!("Switched to layer '{}'", name);
+            *active_layer = name.to_string();
+            self.state_file
+                .save(&active_layer, &self.toggle_states.lock().unwrap());
+        }
+        Ok(())
+    }
+
+    /// MIDI messages handled so far
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    /// MIDI messages handled so far by type ("Note On", "Control Change", "SysEx", "Clock", ...),
+    /// sorted by type
+    pub fn event_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<_> = self
+            .event_counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(kind, count)| (*kind, *count))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Actions fired so far
+    pub fn fired(&self) -> u64 {
+        self.fired.load(Ordering::Relaxed)
+    }
+
+    /// Whether a Quit action has fired. The engine keeps working; stopping it (and calling
+    /// `shutdown`) is up to the caller.
+    pub fn quit_requested(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+
+    /// Commands that failed to start, exited non-zero or timed out, plus failed Http requests
+    pub fn failed_commands(&self) -> u64 {
+        self.commands.failed.load(Ordering::Relaxed)
+    }
+
+    /// Reopen the feedback output and light the Toggle pads again, e.g. after the device
+    /// was unplugged and has come back with its LEDs off
+    pub fn reconnect_feedback(&self) {
+        // The old output is only swapped afterwards so the feedback lock is never held while
+        // taking the others
+        let mut feedback = FeedbackOutput::connect(&self.device, self.dry_run);
+        send_toggle_feedback(
+            &self.mappings.read().unwrap(),
+            &self.active_layer.lock().unwrap(),
+            &self.toggle_states.lock().unwrap(),
+            &mut feedback,
+        );
+        *self.feedback.lock().unwrap() = feedback;
+    }
+
+    /// Release every key held by a KeyHold mapping and every held Modifier, and stop every
+    /// KeyRepeat, e.g. when the device disappears and its Note Offs will never arrive
+    pub fn release_held_keys(&self) {
+        self.held_modifiers.lock().unwrap().clear();
+        self.repeating_keys.lock().unwrap().clear();
+        let mut keyboard = self.keyboard.lock().unwrap();
+        for held in [&self.held_keys, &self.latched_keys] {
+            for code in held.lock().unwrap().drain().flat_map(|(_, codes)| codes) {
+                keyboard.send(&code, KeyState::Release);
+            }
+        }
+    }
+
+    /// Release held keys, drop queued commands and wait briefly for running ones
+    pub fn shutdown(&self) {
+        self.release_held_keys();
+        if !self.commands.shutdown(SHUTDOWN_GRACE) {
+            warn!("Some commands were still running at exit");
+        }
+    }
+
+    // Clock pulses drive the ClockDivider actions mapped to "clock"; Start and Stop rewind them.
+    // Like SysEx, their actions see channel 0 and id "clock".
+    fn handle_clock(&self, status: u8) {
+        if status != CLOCK_TICK {
+            self.clock_ticks.lock().unwrap().clear();
+            return;
+        }
+        let mut keyboard = self.keyboard.lock().unwrap();
+        let mappings = self.mappings.read().unwrap();
+        let active_layer = self.active_layer.lock().unwrap();
+        let held_modifiers = self.held_modifiers.lock().unwrap();
+        let layer = effective_layer(&active_layer, &held_modifiers);
+        let Some(mapping) = mappings.lookup(layer, 0, Control::Clock) else {
+            return;
+        };
+        let actions = match &mapping.action {
+            Action::Multi { actions } => actions.as_slice(),
+            action => std::slice::from_ref(action),
+        };
+        let mut clock_ticks = self.clock_ticks.lock().unwrap();
+        for (index, action) in actions.iter().enumerate() {
+            // The `default` mapping can't be a divider, so it never fires on every tick
+            let Action::ClockDivider { ticks, action } = action else {
+                continue;
+            };
+            let count = clock_ticks.entry((0, Control::Clock, index)).or_insert(0);
+            let fire = *count == 0;
+            *count = (*count + 1) % ticks;
+            if fire {
+                self.fired.fetch_add(1, Ordering::Relaxed);
+                debug!("Clock -> {:?}", action);
+                run_step(action, &mut keyboard, &self.commands, 0, Control::Clock, 0);
+            }
+        }
+    }
+
+    // SysEx has no channel or control id, so its actions see channel 0 and id "sysex"
+    fn handle_sysex(&self, msg: &[u8]) {
+        let mut keyboard = self.keyboard.lock().unwrap();
+        let mappings = self.mappings.read().unwrap();
+        let Some(action) = mappings.lookup_sysex(msg) else {
+            if self.learn {
+                print_sysex_suggestion(msg);
+            }
+            trace!("SysEx {} (unmapped)", sysex_hex(msg));
+            return;
+        };
+        self.fired.fetch_add(1, Ordering::Relaxed);
+        debug!("SysEx {} -> {:?}", sysex_hex(msg), action);
+        run_step(action, &mut keyboard, &self.commands, 0, Control::SysEx, 0);
+    }
+
+    /// Handle one raw MIDI message, as received from the device
+    pub fn handle_message(&self, msg: &[u8]) {
+        let Engine {
+            keyboard,
+            commands,
+            volume,
+            feedback,
+            thru,
+            osc,
+            state_file,
+            active_layer,
+            held_modifiers,
+            toggle_states,
+            last_knob_vals,
+            last_linear_texts,
+            smoothed_vals,
+            pickup,
+            last_relative_vals,
+            last_steps,
+            last_triggered,
+            last_fired,
+            cc_msb,
+            cc_high,
+            ghost_notes,
+            pending_presses,
+            tap_times,
+            group_states,
+            held_keys,
+            latched_keys,
+            repeating_keys,
+            events,
+            fired,
+            quit,
+            clock,
+            ..
+        } = self;
+        // One reading for the whole message, so every timed check agrees on it
+        let now = clock.now();
+        let decoded = decode(msg);
+        let kind = match (decoded, msg.first()) {
+            (Some(event), _) => event.name(),
+            (None, Some(&SYSEX_START)) => "SysEx",
+            (None, Some(&(CLOCK_TICK | CLOCK_START | CLOCK_CONTINUE | CLOCK_STOP))) => "Clock",
+            _ => "Other",
+        };
+        events.fetch_add(1, Ordering::Relaxed);
+        *self.event_counts.lock().unwrap().entry(kind).or_insert(0) += 1;
+        if let Some(thru) = &thru {
+            let mut thru = thru.lock().unwrap();
+            // Clock, SysEx and anything else without a control id always goes through
+            let mapped = thru.skip_mapped
+                && decoded.is_some_and(|event| {
+                    self.mappings
+                        .read()
+                        .unwrap()
+                        .lookup(
+                            effective_layer(
+                                &active_layer.lock().unwrap(),
+                                &held_modifiers.lock().unwrap(),
+                            ),
+                            event.channel(),
+                            event.control(),
+                        )
+                        .is_some()
+                });
+            if !mapped {
+                thru.send(msg);
+            }
+        }
+        if msg.first() == Some(&SYSEX_START) {
+            self.handle_sysex(msg);
+            return;
+        }
+        if let Some(&status @ (CLOCK_TICK | CLOCK_START | CLOCK_STOP)) = msg.first() {
+            self.handle_clock(status);
+            return;
+        }
+        let Some(event) = decoded else { return };
+        let (channel, mut id) = (event.channel(), event.control());
+        // `raw_val` is the 7-bit value; `input` is the full-resolution value for Linear scaling
+        let (mut raw_val, mut input, mut input_max) = event.value();
+        let mut keyboard = keyboard.lock().unwrap();
+        // Aftertouch sets the repeat speed of held KeyRepeat pads: a note's own pressure, or
+        // channel pressure for every pad on the channel
+        if let MidiEvent::PolyPressure { pressure, .. }
+        | MidiEvent::ChannelPressure { pressure, .. } = event
+        {
+            let note = match event {
+                MidiEvent::PolyPressure { note, .. } => Some(Control::Id(note)),
+                _ => None,
+            };
+            for (&(ch, control, _), (_, held)) in repeating_keys.lock().unwrap().iter_mut() {
+                if ch == channel && note.is_none_or(|note| note == control) {
+                    *held = pressure;
+                }
+            }
+        }
+
+        let mappings = self.mappings.read().unwrap();
+        let mut active_layer = active_layer.lock().unwrap();
+        let mut held_modifiers = held_modifiers.lock().unwrap();
+        // A 14-bit pair drives the MSB's mapping. As in the MIDI spec, a new MSB applies at once
+        // with an LSB of 0, and each LSB then refines the last MSB.
+        if let MidiEvent::ControlChange {
+            controller, value, ..
+        } = event
+        {
+            let layer = effective_layer(&active_layer, &held_modifiers);
+            if let Some(pair) = mappings.high_res_pair(layer, channel, controller) {
+                let msb_key = (channel, Control::Id(pair.msb));
+                let mut cc_msb = cc_msb.lock().unwrap();
+                let (msb, lsb) = if controller == pair.msb {
+                    cc_msb.insert(msb_key, value);
+                    (value, 0)
+                } else {
+                    (cc_msb.get(&msb_key).copied().unwrap_or(0), value)
+                };
+                id = Control::Id(pair.msb);
+                raw_val = msb;
+                input = (msb as u16) << 7 | lsb as u16;
+                input_max = PITCH_BEND_MAX;
+            }
+        }
+        let (mapping_layer, mapping) = mappings
+            .lookup_in(effective_layer(&active_layer, &held_modifiers), channel, id)
+            .unzip();
+
+        let released = matches!(event, MidiEvent::NoteOff { .. });
+        let action = if released {
+            // Release whatever this note pressed, even if the mapping changed since
+            for code in held_keys
+                .lock()
+                .unwrap()
+                .remove(&(channel, id))
+                .unwrap_or_default()
+            {
+                debug!("Note Off ch{} id {} -> release {}", channel, id, code);
+                keyboard.send(&code, KeyState::Release);
+            }
+            repeating_keys
+                .lock()
+                .unwrap()
+                .retain(|&(ch, control, _), _| (ch, control) != (channel, id));
+            if ghost_notes.lock().unwrap().remove(&(channel, id)) {
+                return;
+            }
+            // Checked before the lookup, which would otherwise happen in the modifier's own layer
+            if let Some(pos) = held_modifiers
+                .iter()
+                .position(|(key, _)| *key == (channel, id))
+            {
+                let (_, layer) = held_modifiers.remove(pos);
+                debug!("Released modifier for layer '{}'", layer);
+                return;
+            }
+            match mapping {
+                Some(Mapping {
+                    action:
+                        action @ (Action::Toggle {
+                            mode: ToggleMode::Momentary,
+                            ..
+                        }
+                        | Action::Press { .. }),
+                    ..
+                }) => action,
+                Some(Mapping {
+                    on_release: Some(action),
+                    ..
+                }) => action,
+                _ => return,
+            }
+        } else {
+            let Some(mapping) = mapping else {
+                if self.learn {
+                    print_suggestion(msg, &event);
+                }
+                trace!(
+                    "{} ch{} id {} value {} (unmapped)",
+                    event.name(),
+                    channel,
+                    id,
+                    input
+                );
+                return;
+            };
+            if let MidiEvent::NoteOn { .. } = event {
+                let mut ghost_notes = ghost_notes.lock().unwrap();
+                if raw_val < mapping.min_velocity.unwrap_or(1) {
+                    debug!("Ghost hit ch{} id {} velocity {}", channel, id, raw_val);
+                    ghost_notes.insert((channel, id));
+                    return;
+                }
+                ghost_notes.remove(&(channel, id));
+            }
+            if let (MidiEvent::ControlChange { value, .. }, Some(trigger)) =
+                (event, mapping.trigger)
+            {
+                // Buttons start out released
+                let high = value >= 64;
+                let was_high = cc_high
+                    .lock()
+                    .unwrap()
+                    .insert((channel, id), high)
+                    .unwrap_or(false);
+                let fire = match trigger {
+                    CcTrigger::Rising => high && !was_high,
+                    CcTrigger::Falling => !high && was_high,
+                    CcTrigger::Change => high != was_high,
+                };
+                if !fire {
+                    return;
+                }
+            }
+            if too_soon(
+                last_triggered,
+                (channel, id),
+                now,
+                mapping.debounce_ms,
+                true,
+            ) {
+                debug!("Debounced ch{} id {}", channel, id);
+                return;
+            }
+            if too_soon(last_fired, (channel, id), now, mapping.cooldown_ms, false) {
+                debug!("Cooling down ch{} id {}", channel, id);
+                return;
+            }
+            // Relative decoding keeps using the raw value
+            if let Some(curve) = mapping.curve {
+                input = curve.apply(input, input_max);
+            }
+            &mapping.action
+        };
+
+        // A Multi mapping fires each of its actions in order
+        let actions = match action {
+            Action::Multi { actions } => actions.as_slice(),
+            single => std::slice::from_ref(single),
+        };
+        for (index, action) in actions.iter().enumerate() {
+            // Per-action state, so the actions of a Multi don't share dedup or step history
+            let key = (channel, id, index);
+            fired.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                event_type = event.name(),
+                channel = channel,
+                id:% = id,
+                value = input,
+                action:? = action;
+                "{} ch{} id {} value {} -> {:?}",
+                event.name(),
+                channel,
+                id,
+                input,
+                action
+            );
+
+            match action {
+                Action::Key { code, latch: true } | Action::KeyHold { code, latch: true } => {
+                    let mut latched = latched_keys.lock().unwrap();
+                    let codes = latched.entry((channel, id)).or_default();
+                    if let Some(pos) = codes.iter().position(|c| c == code) {
+                        debug!("Unlatched {}", code);
+                        keyboard.send(code, KeyState::Release);
+                        codes.remove(pos);
+                    } else {
+                        debug!("Latched {}", code);
+                        keyboard.send(code, KeyState::Press);
+                        codes.push(code.clone());
+                    }
+                }
+                Action::Key { .. }
+                | Action::Chord { .. }
+                | Action::Command { .. }
+                | Action::Exec { .. }
+                | Action::Http { .. } => {
+                    run_step(action, &mut keyboard, commands, channel, id, input)
+                }
+                Action::Sequence { steps, delay_ms } => {
+                    let steps = steps.clone();
+                    let delay = Duration::from_millis(delay_ms.unwrap_or(0));
+                    let keyboard = Arc::clone(&self.keyboard);
+                    let commands = commands.clone();
+                    thread::spawn(move || {
+                        for (i, step) in steps.iter().enumerate() {
+                            if i > 0 && !delay.is_zero() {
+                                thread::sleep(delay);
+                            }
+                            let mut keyboard = keyboard.lock().unwrap();
+                            run_step(step, &mut keyboard, &commands, channel, id, input);
+                        }
+                    });
+                }
+                Action::Conditional {
+                    check_cmd,
+                    then,
+                    otherwise,
+                } => {
+                    let check =
+                        CommandLine::Shell(expand_placeholders(check_cmd, channel, id, input));
+                    let (then, otherwise) = (then.clone(), otherwise.clone());
+                    let keyboard = Arc::clone(&self.keyboard);
+                    let commands = commands.clone();
+                    thread::spawn(move || {
+                        let branch = if check_passes(&check, &commands) {
+                            Some(&then)
+                        } else {
+                            otherwise.as_ref()
+                        };
+                        if let Some(action) = branch {
+                            let mut keyboard = keyboard.lock().unwrap();
+                            run_step(action, &mut keyboard, &commands, channel, id, input);
+                        }
+                    });
+                }
+                Action::Press {
+                    short,
+                    long,
+                    threshold_ms,
+                } => {
+                    let threshold =
+                        Duration::from_millis(threshold_ms.unwrap_or(DEFAULT_LONG_PRESS_MS));
+                    if released {
+                        // Still pending means the long press hasn't fired yet. If it was held
+                        // long enough, the timer below just hasn't woken up.
+                        if let Some(pressed_at) = pending_presses.lock().unwrap().remove(&key) {
+                            let step = if now.duration_since(pressed_at) >= threshold {
+                                long
+                            } else {
+                                short
+                            };
+                            run_step(step, &mut keyboard, commands, channel, id, input);
+                        }
+                        continue;
+                    }
+                    let pressed_at = now;
+                    pending_presses.lock().unwrap().insert(key, pressed_at);
+                    let long = long.clone();
+                    let pending = Arc::clone(pending_presses);
+                    let keyboard = Arc::clone(&self.keyboard);
+                    let commands = commands.clone();
+                    thread::spawn(move || {
+                        thread::sleep(threshold);
+                        // A release or a newer press in the meantime leaves nothing to do
+                        let mut pending = pending.lock().unwrap();
+                        if pending.get(&key) == Some(&pressed_at) {
+                            pending.remove(&key);
+                            drop(pending);
+                            let mut keyboard = keyboard.lock().unwrap();
+                            run_step(&long, &mut keyboard, &commands, channel, id, input);
+                        }
+                    });
+                }
+                Action::KeyHold { code, .. } => {
+                    let mut held = held_keys.lock().unwrap();
+                    let codes = held.entry((channel, id)).or_default();
+                    if !codes.contains(code) {
+                        keyboard.send(code, KeyState::Press);
+                        codes.push(code.clone());
+                    }
+                }
+                Action::KeyRepeat {
+                    code,
+                    rate_ms,
+                    initial_delay_ms,
+                    pressure_rate_ms,
+                } => {
+                    keyboard.send(code, KeyState::Click);
+                    let pressed_at = now;
+                    repeating_keys.lock().unwrap().insert(key, (pressed_at, 0));
+                    let delay = Duration::from_millis(
+                        initial_delay_ms.unwrap_or(DEFAULT_KEY_REPEAT_DELAY_MS),
+                    );
+                    let rate = rate_ms.unwrap_or(DEFAULT_KEY_REPEAT_MS);
+                    let pressure_rate = pressure_rate_ms.unwrap_or(rate);
+                    let code = code.clone();
+                    let repeating = Arc::clone(repeating_keys);
+                    let keyboard = Arc::clone(&self.keyboard);
+                    thread::spawn(move || {
+                        thread::sleep(delay);
+                        loop {
+                            // A release or a newer press of the pad ends this repeat
+                            let pressure = match repeating.lock().unwrap().get(&key) {
+                                Some(&(at, pressure)) if at == pressed_at => pressure,
+                                _ => break,
+                            };
+                            keyboard.lock().unwrap().send(&code, KeyState::Click);
+                            let fraction = pressure as f64 / 127.0;
+                            let interval =
+                                rate as f64 + (pressure_rate as f64 - rate as f64) * fraction;
+                            thread::sleep(Duration::from_millis(interval.round() as u64));
+                        }
+                    });
+                }
+                Action::Volume { sink, max } => {
+                    let max = max.unwrap_or(100);
+                    let percent = scale_linear(input, input_max, 0, max as i64, false) as u32;
+                    let mut cache = last_knob_vals.lock().unwrap();
+                    if cache.insert(key, percent as i64) != Some(percent as i64) {
+                        volume.set(
+                            sink.as_deref().unwrap_or("@DEFAULT_SINK@"),
+                            percent,
+                            commands,
+                            (channel, id),
+                            index,
+                        );
+                    }
+                }
+                Action::Steps { commands: steps } => {
+                    let band = input as usize * steps.len() / (input_max as usize + 1);
+                    let mut cache = last_knob_vals.lock().unwrap();
+                    if cache.insert(key, band as i64) != Some(band as i64) {
+                        let cmd = expand_placeholders(&steps[band], channel, id, input);
+                        // Only the band the fader ends up in matters if it outruns the workers
+                        commands.push(QueuedCommand {
+                            coalesce: Some(index),
+                            ..QueuedCommand::new((channel, id), CommandLine::Shell(cmd))
+                        });
+                    }
+                }
+                Action::FaderGroup {
+                    template,
+                    settle_ms,
+                    members,
+                } => {
+                    let Some(position) = members.iter().position(|member| *member == id) else {
+                        continue;
+                    };
+                    let group = (channel, members[0], index);
+                    let mut states = group_states.lock().unwrap();
+                    let state = states.entry(group).or_default();
+                    // A reload can change the group's size
+                    state.values.resize(members.len(), 0);
+                    if state.values[position] == input {
+                        continue;
+                    }
+                    state.values[position] = input;
+                    if state.pending {
+                        continue;
+                    }
+                    state.pending = true;
+                    let settle =
+                        Duration::from_millis(settle_ms.unwrap_or(DEFAULT_GROUP_SETTLE_MS));
+                    let template = template.clone();
+                    let states = Arc::clone(group_states);
+                    let commands = commands.clone();
+                    thread::spawn(move || {
+                        thread::sleep(settle);
+                        let values = {
+                            let mut states = states.lock().unwrap();
+                            let Some(state) = states.get_mut(&group) else {
+                                return;
+                            };
+                            state.pending = false;
+                            state.values.clone()
+                        };
+                        let mut cmd = template.replace(
+                            "{values}",
+                            &values
+                                .iter()
+                                .map(u16::to_string)
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                        );
+                        for (i, value) in values.iter().enumerate() {
+                            cmd = cmd.replace(&format!("{{v{}}}", i), &value.to_string());
+                        }
+                        commands.push(QueuedCommand {
+                            coalesce: Some(index),
+                            ..QueuedCommand::new(
+                                (group.0, group.1),
+                                CommandLine::Shell(expand_placeholders(&cmd, channel, id, input)),
+                            )
+                        });
+                    });
+                }
+                Action::Linear {
+                    soft_takeover,
+                    smoothing,
+                    ..
+                } => {
+                    // The smoothed value stands in for the reading from here on, {value} too
+                    let (input, settle) = match smoothing {
+                        Some(smoothing) => {
+                            let mut states = smoothed_vals.lock().unwrap();
+                            let state = states.entry(key).or_default();
+                            let smoothed = state.update(input, input_max, *smoothing);
+                            let settle = (!state.settling && smoothed != input).then(|| {
+                                state.settling = true;
+                                state.generation
+                            });
+                            (smoothed, settle)
+                        }
+                        None => (input, None),
+                    };
+                    // A lookup only fails for releases, which never reach a Linear
+                    let layer = mapping_layer.unwrap_or(DEFAULT_LAYER);
+                    if *soft_takeover && !pickup.lock().unwrap().engaged(layer, key, input) {
+                        trace!("ch{} id {} waiting for soft takeover", channel, id);
+                        continue;
+                    }
+                    send_linear(
+                        action,
+                        key,
+                        input,
+                        input_max,
+                        last_knob_vals,
+                        last_linear_texts,
+                        commands,
+                    );
+                    if let Some(generation) = settle {
+                        SmoothingSettle {
+                            action: action.clone(),
+                            key,
+                            layer: layer.to_string(),
+                            input_max,
+                            generation,
+                            states: Arc::clone(smoothed_vals),
+                            pickup: Arc::clone(pickup),
+                            last_knob_vals: Arc::clone(last_knob_vals),
+                            last_linear_texts: Arc::clone(last_linear_texts),
+                            commands: commands.clone(),
+                            clock: Arc::clone(clock),
+                        }
+                        .schedule();
+                    }
+                }
+                Action::Relative {
+                    inc_cmd,
+                    dec_cmd,
+                    cmd,
+                    mode,
+                    repeat,
+                    acceleration,
+                    timeout_ms,
+                } => {
+                    let mut delta = control_step(raw_val, *mode, last_relative_vals, key);
+                    if delta == 0 {
+                        continue;
+                    }
+                    if let Some(acceleration) = acceleration {
+                        delta = accelerate(delta, acceleration, last_steps, key, now);
+                    }
+                    let times = if *repeat {
+                        delta.unsigned_abs().min(MAX_RELATIVE_REPEAT)
+                    } else {
+                        1
+                    };
+                    // Validation guarantees either cmd or both inc_cmd and dec_cmd
+                    let (cmd, shown) = match (cmd, delta > 0) {
+                        (Some(cmd), _) => (cmd, delta),
+                        (None, true) => (inc_cmd.as_ref().unwrap(), delta.abs()),
+                        (None, false) => (dec_cmd.as_ref().unwrap(), delta.abs()),
+                    };
+                    let final_cmd = expand_placeholders(
+                        &cmd.replace("{delta}", &shown.to_string()),
+                        channel,
+                        id,
+                        input,
+                    );
+                    for _ in 0..times {
+                        commands.push(QueuedCommand {
+                            timeout: timeout_ms.map(Duration::from_millis),
+                            ..QueuedCommand::new(
+                                (channel, id),
+                                CommandLine::Shell(final_cmd.clone()),
+                            )
+                        });
+                    }
+                }
+                Action::Toggle {
+                    on_cmd,
+                    off_cmd,
+                    initial,
+                    mode,
+                    timeout_ms,
+                    feedback: toggle_feedback,
+                } => {
+                    let mut states = toggle_states.lock().unwrap();
+                    let state = states.entry(key).or_insert(*initial);
+                    *state = match mode {
+                        ToggleMode::Latch => !*state,
+                        ToggleMode::Momentary => !released,
+                    };
+                    if let (Some(fb), Control::Id(note)) = (toggle_feedback, id) {
+                        let velocity = if *state { fb.on } else { fb.off };
+                        feedback
+                            .lock()
+                            .unwrap()
+                            .send(channel, fb.note.unwrap_or(note), velocity);
+                    }
+                    let cmd = if *state { on_cmd } else { off_cmd };
+                    state_file.save(&active_layer, &states);
+                    commands.push(QueuedCommand {
+                        timeout: timeout_ms.map(Duration::from_millis),
+                        ..QueuedCommand::new(
+                            (channel, id),
+                            CommandLine::Shell(expand_placeholders(cmd, channel, id, input)),
+                        )
+                    });
+                }
+                Action::TapTempo {
+                    cmd,
+                    taps,
+                    reset_ms,
+                    timeout_ms,
+                } => {
+                    let mut tap_times = tap_times.lock().unwrap();
+                    let times = tap_times.entry(key).or_default();
+                    let reset = Duration::from_millis(reset_ms.unwrap_or(DEFAULT_TAP_RESET_MS));
+                    if times.back().is_some_and(|last| now - *last > reset) {
+                        times.clear();
+                    }
+                    times.push_back(now);
+                    // `taps` intervals need one more timestamp than that
+                    while times.len() > taps.unwrap_or(DEFAULT_TAPS) + 1 {
+                        times.pop_front();
+                    }
+                    // The first tap only starts the clock
+                    let intervals = times.len() - 1;
+                    if intervals == 0 {
+                        continue;
+                    }
+                    let average = (now - times[0]).as_secs_f64() / intervals as f64;
+                    let bpm = (60.0 / average).round() as u32;
+                    commands.push(QueuedCommand {
+                        timeout: timeout_ms.map(Duration::from_millis),
+                        ..QueuedCommand::new(
+                            (channel, id),
+                            CommandLine::Shell(expand_placeholders(
+                                &cmd.replace("{bpm}", &bpm.to_string()),
+                                channel,
+                                id,
+                                input,
+                            )),
+                        )
+                    });
+                }
+                // Only clock pulses drive dividers, in `handle_clock`
+                Action::ClockDivider { .. } => {}
+                Action::Quit => {
+                    info!("Quit requested by ch{} id {}", channel, id);
+                    quit.store(true, Ordering::Relaxed);
+                }
+                Action::VelocityBands { bands } => {
+                    // Listed order doesn't matter, the highest threshold reached wins
+                    let band = bands
+                        .iter()
+                        .filter(|(min, _)| input >= *min as u16)
+                        .max_by_key(|(min, _)| *min);
+                    match band {
+                        Some((_, step)) => {
+                            run_step(step, &mut keyboard, commands, channel, id, input)
+                        }
+                        None => debug!(
+                            "ch{} id {} velocity {} is below every band",
+                            channel, id, input
+                        ),
+                    }
+                }
+                Action::Modifier { layer } => {
+                    if !held_modifiers.iter().any(|(key, _)| *key == (channel, id)) {
+                        debug!("Holding layer '{}'", layer);
+                        held_modifiers.push(((channel, id), layer.clone()));
+                    }
+                }
+                Action::Layer { name } => {
+                    if *active_layer != *name {
+                        info!("Switched to layer '{}'", name);
+                        *active_layer = name.clone();
+                        state_file.save(&active_layer, &toggle_states.lock().unwrap());
+                    }
+                }
+                // Already flattened above, and validation rejects nesting
+                Action::Multi { .. } => {}
+                Action::Osc {
+                    address,
+                    path,
+                    args,
+                } => {
+                    let normalized = (input as f64 / input_max as f64).to_string();
+                    let expand = |template: &str| {
+                        expand_placeholders(
+                            &template.replace("{normalized}", &normalized),
+                            channel,
+                            id,
+                            input,
+                        )
+                    };
+                    let args: Result<Vec<OscType>, Box<dyn std::error::Error>> = args
+                        .iter()
+                        .map(|arg| {
+                            Ok(match arg {
+                                OscArg::Int(t) => OscType::Int(expand(t).trim().parse()?),
+                                OscArg::Float(t) => OscType::Float(expand(t).trim().parse()?),
+                                OscArg::String(t) => OscType::String(expand(t)),
+                            })
+                        })
+                        .collect();
+                    match args {
+                        Ok(args) => osc.lock().unwrap().send(address, path, args),
+                        Err(e) => error!("Bad OSC argument for {}: {}", path, e),
+                    }
+                }
+                Action::Scroll { axis, amount, mode } => {
+                    // Knobs move by how far they turned; pads and programs by one step
+                    let step = if let MidiEvent::ControlChange { .. } = event {
+                        control_step(raw_val, *mode, last_relative_vals, key)
+                    } else {
+                        1
+                    };
+                    if step != 0 {
+                        keyboard.scroll(*axis, step * amount);
+                    }
+                }
+                Action::MouseMove {
+                    axis,
+                    sensitivity,
+                    mode,
+                } => {
+                    let step = if let MidiEvent::ControlChange { .. } = event {
+                        control_step(raw_val, *mode, last_relative_vals, key)
+                    } else {
+                        1
+                    };
+                    if step != 0 {
+                        keyboard.move_pointer(*axis, step * sensitivity);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Run a Conditional's check, treating exit status 0 as true. In dry-run mode the
+// check is only logged and assumed to pass.
+fn check_passes(check: &CommandLine, commands: &CommandQueue) -> bool {
+    if commands.dry_run {
+        info!("[dry-run] Would check: {}", check);
+        return true;
+    }
+    match check
+        .to_command(commands.shell.as_deref())
+        .stdout(Stdio::null())
+        .status()
+    {
+        Ok(status) => {
+            debug!("Check '{}' -> {}", check, status);
+            status.success()
+        }
+        Err(e) => {
+            error!("Failed to run check '{}': {}", check, e);
+            false
+        }
+    }
+}
+
+// Fire a one-shot action: used for plain mappings, each step of a Sequence and Conditional branches
+fn run_step(
+    action: &Action,
+    keyboard: &mut VirtualKeyboard,
+    commands: &CommandQueue,
+    channel: u8,
+    id: Control,
+    input: u16,
+) {
+    match action {
+        Action::Key { code, .. } => keyboard.send(code, KeyState::Click),
+        Action::Chord { codes } => keyboard.send_chord(codes),
+        Action::Command {
+            cmd,
+            timeout_ms,
+            cwd,
+            env,
+            singleton,
+            on_error,
+        } => commands.push(QueuedCommand {
+            singleton: *singleton,
+            on_error: on_error
+                .as_ref()
+                .map(|on_error| expand_placeholders(on_error, channel, id, input)),
+            timeout: timeout_ms.map(Duration::from_millis),
+            cwd: cwd.as_deref().map(|dir| PathBuf::from(expand_env(dir))),
+            env: env.clone(),
+            ..QueuedCommand::new(
+                (channel, id),
+                CommandLine::Shell(expand_placeholders(cmd, channel, id, input)),
+            )
+        }),
+        Action::Exec {
+            program,
+            args,
+            timeout_ms,
+            cwd,
+            env,
+            singleton,
+            on_error,
+        } => commands.push(QueuedCommand {
+            singleton: *singleton,
+            on_error: on_error
+                .as_ref()
+                .map(|on_error| expand_placeholders(on_error, channel, id, input)),
+            timeout: timeout_ms.map(Duration::from_millis),
+            cwd: cwd.as_deref().map(|dir| PathBuf::from(expand_env(dir))),
+            env: env.clone(),
+            ..QueuedCommand::new(
+                (channel, id),
+                CommandLine::Exec(
+                    expand_env(program),
+                    args.iter()
+                        .map(|arg| expand_placeholders(&expand_env(arg), channel, id, input))
+                        .collect(),
+                ),
+            )
+        }),
+        Action::Http {
+            url,
+            method,
+            body,
+            timeout_ms,
+        } => commands.push(QueuedCommand {
+            timeout: Some(timeout_ms.map_or(HTTP_TIMEOUT, Duration::from_millis)),
+            ..QueuedCommand::new(
+                (channel, id),
+                CommandLine::Http {
+                    method: method.as_deref().unwrap_or("POST").to_uppercase(),
+                    url: expand_placeholders(url, channel, id, input),
+                    body: body
+                        .as_ref()
+                        .map(|body| expand_placeholders(body, channel, id, input)),
+                },
+            )
+        }),
+        Action::Scroll { axis, amount, .. } => keyboard.scroll(*axis, *amount),
+        Action::MouseMove {
+            axis, sensitivity, ..
+        } => keyboard.move_pointer(*axis, *sensitivity),
+        // Stateful actions are rejected as Sequence steps, Conditional/Press branches and SysEx
+        // actions by validation
+        _ => {}
+    }
+}
+
+#[derive(Clone, Copy)]
+enum KeyState {
+    Press,
+    Release,
+    Click,
+}
+
+/// Platform key emitter: a uinput device on Linux, enigo on macOS/Windows
+struct VirtualKeyboard {
+    // Log keys instead of sending them
+    dry_run: bool,
+    // Not created at all in dry-run mode
+    #[cfg(target_os = "linux")]
+    device: Option<VirtualDevice>,
+    #[cfg(target_os = "linux")]
+    identity: VirtualDeviceConfig,
+    #[cfg(target_os = "linux")]
+    keys: AttributeSet<EvdevKey>,
+    #[cfg(target_os = "linux")]
+    axes: AttributeSet<RelativeAxisType>,
+}
+
+impl VirtualKeyboard {
+    #[cfg(target_os = "linux")]
+    fn new<'a>(
+        actions: impl Iterator<Item = &'a Action>,
+        identity: &VirtualDeviceConfig,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let mut keyboard = Self {
+            dry_run,
+            device: None,
+            identity: identity.clone(),
+            keys: AttributeSet::new(),
+            axes: AttributeSet::new(),
+        };
+        keyboard.register(actions);
+        if !dry_run {
+            keyboard.device = Some(keyboard.build_device()?);
+        }
+        Ok(keyboard)
+    }
+
+    // Unknown key codes are already rejected by config validation
+    #[cfg(not(target_os = "linux"))]
+    fn new<'a>(
+        _actions: impl Iterator<Item = &'a Action>,
+        _identity: &VirtualDeviceConfig,
+        dry_run: bool,
+    ) -> Result<Self> {
+        Ok(Self { dry_run })
+    }
+
+    // uinput only delivers keys and axes that were registered when the device was built.
+    // Returns true if the actions needed any the device doesn't have yet.
+    #[cfg(target_os = "linux")]
+    fn register<'a>(&mut self, actions: impl Iterator<Item = &'a Action>) -> bool {
+        let mut added = false;
+        for action in actions.flat_map(Action::with_nested) {
+            let mut keys: Vec<EvdevKey> = action
+                .key_codes()
+                .iter()
+                .filter_map(|c| c.parse().ok())
+                .collect();
+            let axis = match action {
+                Action::Scroll { axis, .. } => Some(axis.wheel()),
+                Action::MouseMove { axis, .. } => {
+                    // libinput only treats devices with a button as pointers
+                    keys.push(EvdevKey::BTN_LEFT);
+                    Some(axis.pointer())
+                }
+                _ => None,
+            };
+            for key in keys {
+                if !self.keys.contains(key) {
+                    self.keys.insert(key);
+                    added = true;
+                }
+            }
+            if let Some(axis) = axis {
+                if !self.axes.contains(axis) {
+                    self.axes.insert(axis);
+                    added = true;
+                }
+            }
+        }
+        added
+    }
+
+    #[cfg(target_os = "linux")]
+    fn build_device(&self) -> Result<VirtualDevice> {
+        let build = || {
+            let identity = &self.identity;
+            let mut builder = VirtualDeviceBuilder::new()?
+                .name(identity.name.as_deref().unwrap_or(VIRTUAL_DEVICE_NAME))
+                .with_keys(&self.keys)?;
+            if identity.vendor.is_some() || identity.product.is_some() {
+                builder = builder.input_id(InputId::new(
+                    BusType::BUS_VIRTUAL,
+                    identity.vendor.unwrap_or(0),
+                    identity.product.unwrap_or(0),
+                    1,
+                ));
+            }
+            if self.axes.iter().next().is_some() {
+                builder = builder.with_relative_axes(&self.axes)?;
+            }
+            builder.build()
+        };
+        build().map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => MidiActionError::UinputPermission(e),
+            _ => MidiActionError::Uinput(e),
+        })
+    }
+
+    // Rebuild the uinput device if the actions reference keys or axes it wasn't created with
+    #[cfg(target_os = "linux")]
+    fn ensure_keys<'a>(&mut self, actions: impl Iterator<Item = &'a Action>) -> Result<()> {
+        if self.register(actions) && !self.dry_run {
+            self.device = Some(self.build_device()?);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn ensure_keys<'a>(&mut self, _actions: impl Iterator<Item = &'a Action>) -> Result<()> {
+        Ok(())
+    }
+
+    fn send(&mut self, code: &str, state: KeyState) {
+        if self.dry_run {
+            let verb = match state {
+                KeyState::Press => "press",
+                KeyState::Release => "release",
+                KeyState::Click => "click",
+            };
+            info!("[dry-run] Would {} {}", verb, code);
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(device) = &mut self.device {
+            if let Ok(key) = code.parse::<EvdevKey>() {
+                let down = InputEvent::new(EvdevEventType::KEY, key.code(), 1i32);
+                let up = InputEvent::new(EvdevEventType::KEY, key.code(), 0i32);
+                let events: &[InputEvent] = match state {
+                    KeyState::Press => &[down],
+                    KeyState::Release => &[up],
+                    KeyState::Click => &[down, up],
+                };
+                if let Err(e) = device.emit(events) {
+                    error!("Failed to emit key: {}", e);
+                }
+            }
+        }
+        #[cfg(any(target_os = "macos", target_os = "windows"))]
+        {
+            if let Some(key) = string_to_enigo_key(code) {
+                let direction = match state {
+                    KeyState::Press => Direction::Press,
+                    KeyState::Release => Direction::Release,
+                    KeyState::Click => Direction::Click,
+                };
+                if let Err(e) = with_enigo(|enigo| enigo.key(key, direction)) {
+                    error!("Failed to simulate key: {}", e);
+                }
+            }
+        }
+    }
+
+    fn send_chord(&mut self, codes: &[String]) {
+        if self.dry_run {
+            info!("[dry-run] Would press chord {}", codes.join("+"));
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(device) = &mut self.device {
+            let keys: Vec<EvdevKey> = codes.iter().filter_map(|c| c.parse().ok()).collect();
+            let downs = keys
+                .iter()
+                .map(|k| InputEvent::new(EvdevEventType::KEY, k.code(), 1i32));
+            let ups = keys
+                .iter()
+                .rev()
+                .map(|k| InputEvent::new(EvdevEventType::KEY, k.code(), 0i32));
+            let events: Vec<InputEvent> = downs.chain(ups).collect();
+            if let Err(e) = device.emit(&events) {
+                error!("Failed to emit chord: {}", e);
+            }
+        }
+        #[cfg(any(target_os = "macos", target_os = "windows"))]
+        {
+            // Hold each key down in order so earlier keys act as modifiers for later ones
+            for code in codes {
+                self.send(code, KeyState::Press);
+            }
+            for code in codes.iter().rev() {
+                self.send(code, KeyState::Release);
+            }
+        }
+    }
+
+    fn scroll(&mut self, axis: Axis, amount: i32) {
+        if self.dry_run {
+            info!("[dry-run] Would scroll {:?} by {}", axis, amount);
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // The wheel counts up as positive, the opposite of screen coordinates
+            let value = match axis {
+                Axis::Vertical => -amount,
+                Axis::Horizontal => amount,
+            };
+            self.emit_relative(axis.wheel(), value);
+        }
+        #[cfg(any(target_os = "macos", target_os = "windows"))]
+        {
+            let axis = match axis {
+                Axis::Horizontal => EnigoAxis::Horizontal,
+                Axis::Vertical => EnigoAxis::Vertical,
+            };
+            if let Err(e) = with_enigo(|enigo| enigo.scroll(amount, axis)) {
+                error!("Failed to simulate scroll: {}", e);
+            }
+        }
+    }
+
+    fn move_pointer(&mut self, axis: Axis, amount: i32) {
+        if self.dry_run {
+            info!("[dry-run] Would move pointer {:?} by {}", axis, amount);
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        self.emit_relative(axis.pointer(), amount);
+        #[cfg(any(target_os = "macos", target_os = "windows"))]
+        {
+            let (x, y) = match axis {
+                Axis::Horizontal => (amount, 0),
+                Axis::Vertical => (0, amount),
+            };
+            if let Err(e) = with_enigo(|enigo| enigo.move_mouse(x, y, Coordinate::Rel)) {
+                error!("Failed to simulate mouse move: {}", e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn emit_relative(&mut self, axis: RelativeAxisType, value: i32) {
+        if let Some(device) = &mut self.device {
+            let event = InputEvent::new(EvdevEventType::RELATIVE, axis.0, value);
+            if let Err(e) = device.emit(&[event]) {
+                error!("Failed to emit motion: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn with_enigo(f: impl FnOnce(&mut Enigo) -> enigo::InputResult<()>) -> Result<()> {
+    let mut enigo =
+        Enigo::new(&Settings::default()).map_err(|e| MidiActionError::Keyboard(e.to_string()))?;
+    f(&mut enigo).map_err(|e| MidiActionError::Keyboard(e.to_string()))
+}
+
+/// Connection back to the controller for LED feedback, if it has an output port
+struct FeedbackOutput {
+    conn: Option<MidiOutputConnection>,
+    dry_run: bool,
+}
+
+impl FeedbackOutput {
+    // Feedback is optional, so a missing output port is only logged
+    fn connect(device: &DeviceMatcher, dry_run: bool) -> Self {
+        let conn = MidiOutput::new("midi-actions-feedback")
+            .map_err(MidiActionError::from)
+            .and_then(|midi_out| {
+                let ports = midi_out.ports();
+                let names: Vec<String> = ports
+                    .iter()
+                    .map(|p| midi_out.port_name(p).unwrap_or_default())
+                    .collect();
+                let port = ports
+                    .iter()
+                    .zip(&names)
+                    .find_map(|(port, name)| device.matches(name).then_some(port))
+                    .ok_or_else(|| {
+                        MidiActionError::device_not_found(&device.to_string(), names.clone())
+                    })?;
+                midi_out
+                    .connect(port, "midir-feedback")
+                    .map_err(|e| MidiActionError::MidiConnect(e.to_string()))
+            });
+        let conn = match conn {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                debug!("MIDI feedback disabled: {}", e);
+                None
+            }
+        };
+        Self { conn, dry_run }
+    }
+
+    fn send(&mut self, channel: u8, note: u8, velocity: u8) {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would send feedback ch{} note {} velocity {}",
+                channel, note, velocity
+            );
+            return;
+        }
+        if let Some(conn) = &mut self.conn {
+            if let Err(e) = conn.send(&[NOTE_ON | channel, note & 0x7f, velocity & 0x7f]) {
+                error!("Failed to send MIDI feedback: {}", e);
+            }
+        }
+    }
+}
+
+// Sender for the PulseAudio thread, queued (sink, percent) levels
+#[cfg(feature = "pulse")]
+type PulseSender = std::sync::mpsc::Sender<(String, u32)>;
+
+/// Sets sink volumes for Volume actions: natively through PulseAudio when built with the
+/// `pulse` feature and a server is reachable, otherwise through `pactl` (`osascript` on macOS)
+#[derive(Clone)]
+struct VolumeControl {
+    // Connected on first use, so configs without Volume actions never touch PulseAudio
+    #[cfg(feature = "pulse")]
+    native: Arc<std::sync::OnceLock<Option<PulseSender>>>,
+    dry_run: bool,
+}
+
+impl VolumeControl {
+    fn new(dry_run: bool) -> Self {
+        Self {
+            #[cfg(feature = "pulse")]
+            native: Arc::default(),
+            dry_run,
+        }
+    }
+
+    fn set(
+        &self,
+        sink: &str,
+        percent: u32,
+        commands: &CommandQueue,
+        source: (u8, Control),
+        index: usize,
+    ) {
+        if self.dry_run {
+            info!("[dry-run] Would set volume of {} to {}%", sink, percent);
+            return;
+        }
+        #[cfg(feature = "pulse")]
+        if let Some(native) = self.native.get_or_init(spawn_pulse_volume) {
+            if native.send((sink.to_string(), percent)).is_ok() {
+                return;
+            }
+        }
+        let cmd = if cfg!(target_os = "macos") {
+            CommandLine::Exec(
+                "osascript".to_string(),
+                vec![
+                    "-e".to_string(),
+                    format!("set volume output volume {}", percent),
+                ],
+            )
+        } else if cfg!(target_os = "windows") {
+            // NirCmd takes the master volume on a 0-65535 scale and can't go past 100%
+            CommandLine::Exec(
+                "nircmd".to_string(),
+                vec![
+                    "setsysvolume".to_string(),
+                    (percent.min(100) * 65535 / 100).to_string(),
+                ],
+            )
+        } else {
+            CommandLine::Exec(
+                "pactl".to_string(),
+                vec![
+                    "set-sink-volume".to_string(),
+                    sink.to_string(),
+                    format!("{}%", percent),
+                ],
+            )
+        };
+        commands.push(QueuedCommand {
+            coalesce: Some(index),
+            ..QueuedCommand::new(source, cmd)
+        });
+    }
+}
+
+// Connect to PulseAudio (or PipeWire's pulse server) on a thread of its own that applies
+// queued levels. Returns None, after logging why, if there's no server to talk to.
+#[cfg(feature = "pulse")]
+fn spawn_pulse_volume() -> Option<PulseSender> {
+    use std::sync::mpsc;
+
+    let (tx, rx) = mpsc::channel::<(String, u32)>();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut mainloop, context) = match pulse_connect() {
+            Ok(connection) => {
+                let _ = ready_tx.send(Ok(()));
+                connection
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        while let Ok(first) = rx.recv() {
+            // Only the latest level per sink matters if a fader outruns the server
+            let mut levels = vec![first];
+            for (sink, percent) in rx.try_iter() {
+                match levels.iter_mut().find(|(s, _)| *s == sink) {
+                    Some(level) => level.1 = percent,
+                    None => levels.push((sink, percent)),
+                }
+            }
+            for (sink, percent) in levels {
+                if let Err(e) = pulse_set_volume(&mut mainloop, &context, &sink, percent) {
+                    error!("Failed to set volume of {}: {}", sink, e);
+                }
+            }
+            if !context.get_state().is_good() {
+                warn!("Lost the PulseAudio connection, Volume actions will run pactl");
+                return;
+            }
+        }
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => {
+            info!("Volume actions use PulseAudio directly");
+            Some(tx)
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "PulseAudio unavailable ({}), Volume actions will run pactl",
+                e
+            );
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(feature = "pulse")]
+fn pulse_connect() -> Result<(
+    libpulse_binding::mainloop::standard::Mainloop,
+    libpulse_binding::context::Context,
+)> {
+    use libpulse_binding::context::{Context, FlagSet, State};
+    use libpulse_binding::mainloop::standard::Mainloop;
+
+    let volume_error = |e: &str| MidiActionError::Volume(e.to_string());
+    let mut mainloop = Mainloop::new().ok_or_else(|| volume_error("can't create a main loop"))?;
+    let mut context = Context::new(&mainloop, "midi-actions")
+        .ok_or_else(|| volume_error("can't create a context"))?;
+    context
+        .connect(None, FlagSet::NOFLAGS, None)
+        .map_err(|e| volume_error(&format!("{}", e)))?;
+    loop {
+        pulse_iterate(&mut mainloop)?;
+        match context.get_state() {
+            State::Ready => return Ok((mainloop, context)),
+            State::Failed | State::Terminated => return Err(volume_error("connection refused")),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "pulse")]
+fn pulse_iterate(mainloop: &mut libpulse_binding::mainloop::standard::Mainloop) -> Result<()> {
+    use libpulse_binding::mainloop::standard::IterateResult;
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) => Err(MidiActionError::Volume("main loop quit".to_string())),
+        IterateResult::Err(e) => Err(MidiActionError::Volume(format!("{}", e))),
+    }
+}
+
+#[cfg(feature = "pulse")]
+fn pulse_wait<T: ?Sized>(
+    mainloop: &mut libpulse_binding::mainloop::standard::Mainloop,
+    op: &libpulse_binding::operation::Operation<T>,
+) -> Result<()> {
+    use libpulse_binding::operation::State;
+    while op.get_state() == State::Running {
+        pulse_iterate(mainloop)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "pulse")]
+fn pulse_set_volume(
+    mainloop: &mut libpulse_binding::mainloop::standard::Mainloop,
+    context: &libpulse_binding::context::Context,
+    sink: &str,
+    percent: u32,
+) -> Result<()> {
+    use libpulse_binding::callbacks::ListResult;
+    use libpulse_binding::volume::{ChannelVolumes, Volume};
+    use std::{cell::Cell, rc::Rc};
+
+    // The new volume needs one entry per channel of the sink
+    let channels = Rc::new(Cell::new(None));
+    let found = Rc::clone(&channels);
+    let op = context
+        .introspect()
+        .get_sink_info_by_name(sink, move |result| {
+            if let ListResult::Item(info) = result {
+                found.set(Some(info.volume.len()));
+            }
+        });
+    pulse_wait(mainloop, &op)?;
+    let channels = channels
+        .get()
+        .ok_or_else(|| MidiActionError::Volume(format!("no such sink '{}'", sink)))?;
+    let mut volumes = ChannelVolumes::default();
+    volumes.set(
+        channels,
+        Volume((Volume::NORMAL.0 as f64 * percent as f64 / 100.0) as u32),
+    );
+    let op = context
+        .introspect()
+        .set_sink_volume_by_name(sink, &volumes, None);
+    pulse_wait(mainloop, &op)
+}
+
+/// Passes incoming MIDI on to another port
+struct ThruOutput {
+    conn: MidiOutputConnection,
+    skip_mapped: bool,
+}
+
+impl ThruOutput {
+    #[cfg(unix)]
+    fn open(config: &ThruConfig) -> Result<Self> {
+        use midir::os::unix::VirtualOutput;
+        let conn = MidiOutput::new("midi-actions-thru")?
+            .create_virtual(&config.port)
+            .map_err(|e| MidiActionError::Thru {
+                port: config.port.clone(),
+                reason: e.to_string(),
+            })?;
+        info!("Forwarding MIDI to virtual port '{}'", config.port);
+        Ok(Self {
+            conn,
+            skip_mapped: config.skip_mapped,
+        })
+    }
+
+    // Windows has no virtual ports, so connect to one made by e.g. loopMIDI
+    #[cfg(not(unix))]
+    fn open(config: &ThruConfig) -> Result<Self> {
+        let midi_out = MidiOutput::new("midi-actions-thru")?;
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|p| {
+                midi_out
+                    .port_name(p)
+                    .is_ok_and(|name| name.contains(&config.port))
+            })
+            .ok_or_else(|| MidiActionError::Thru {
+                port: config.port.clone(),
+                reason: "no output port with that name".to_string(),
+            })?;
+        let conn = midi_out
+            .connect(&port, "midir-thru")
+            .map_err(|e| MidiActionError::Thru {
+                port: config.port.clone(),
+                reason: e.to_string(),
+            })?;
+        info!("Forwarding MIDI to '{}'", config.port);
+        Ok(Self {
+            conn,
+            skip_mapped: config.skip_mapped,
+        })
+    }
+
+    fn send(&mut self, msg: &[u8]) {
+        if let Err(e) = self.conn.send(msg) {
+            error!("Failed to forward MIDI: {}", e);
+        }
+    }
+}
+
+// Light the feedback LEDs of toggles that are on, for a device that just (re)connected
+fn send_toggle_feedback(
+    layers: &Layers,
+    active_layer: &str,
+    toggles: &ToggleStates,
+    feedback: &mut FeedbackOutput,
+) {
+    for (&(channel, id, index), &on) in toggles {
+        let Control::Id(note) = id else { continue };
+        let Some(mapping) = layers.lookup(active_layer, channel, id) else {
+            continue;
+        };
+        let action = match &mapping.action {
+            Action::Multi { actions } => actions.get(index),
+            single => (index == 0).then_some(single),
+        };
+        if let Some(Action::Toggle {
+            feedback: Some(fb), ..
+        }) = action
+        {
+            let velocity = if on { fb.on } else { fb.off };
+            feedback.send(channel, fb.note.unwrap_or(note), velocity);
+        }
+    }
+}
+
+/// Toggle states and the active layer, saved to `state_file` so they survive a restart
+#[derive(Clone)]
+struct StateFile {
+    path: Option<PathBuf>,
+    // Nothing is written in dry-run mode
+    dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedState {
+    layer: Option<String>,
+    #[serde(default)]
+    toggles: Vec<SavedToggle>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedToggle {
+    channel: u8,
+    id: String,
+    // Position within a Multi
+    #[serde(default)]
+    action: usize,
+    on: bool,
+}
+
+impl StateFile {
+    fn load(&self) -> (Option<String>, ToggleStates) {
+        let Some(path) = &self.path else {
+            return Default::default();
+        };
+        // Nothing has been saved yet
+        let Ok(text) = fs::read_to_string(path) else {
+            return Default::default();
+        };
+        let saved: SavedState = match serde_json::from_str(&text) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Ignoring unreadable state file {}: {}", path.display(), e);
+                return Default::default();
+            }
+        };
+        let toggles = saved
+            .toggles
+            .into_iter()
+            .filter_map(|t| Some(((t.channel, t.id.parse().ok()?, t.action), t.on)))
+            .collect();
+        (saved.layer, toggles)
+    }
+
+    fn save(&self, layer: &str, toggles: &ToggleStates) {
+        let Some(path) = self.path.as_ref().filter(|_| !self.dry_run) else {
+            return;
+        };
+        let mut toggles: Vec<_> = toggles
+            .iter()
+            .map(|(&(channel, id, action), &on)| SavedToggle {
+                channel,
+                id: id.to_string(),
+                action,
+                on,
+            })
+            .collect();
+        toggles.sort_by(|a, b| (a.channel, &a.id, a.action).cmp(&(b.channel, &b.id, b.action)));
+        let state = SavedState {
+            layer: Some(layer.to_string()),
+            toggles,
+        };
+        // Write then rename, so a crash mid-write can't leave a truncated file behind
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string_pretty(&state)
+            .map_err(io::Error::from)
+            .and_then(|json| {
+                fs::write(&tmp, json)?;
+                fs::rename(&tmp, path)
+            });
+        if let Err(e) = result {
+            error!("Failed to write state file {}: {}", path.display(), e);
+        }
+    }
+}
+
+enum CommandLine {
+    // Run through `sh -c`, or `cmd /C` on Windows
+    Shell(String),
+    // Program and arguments, passed as-is
+    Exec(String, Vec<String>),
+    // Sent by the worker itself instead of spawning anything
+    Http {
+        method: String,
+        url: String,
+        body: Option<String>,
+    },
+}
+
+impl CommandLine {
+    // Children get no stdin and, on Unix, a process group of their own, so a Ctrl+C meant for
+    // the daemon (or a terminal closing) doesn't take the programs it launched with it.
+    // The command queue's reaper waits on every child, so none is left as a zombie.
+    fn to_command(&self, shell: Option<&ShellConfig>) -> Command {
+        let mut command = self.program(shell);
+        command.stdin(Stdio::null());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        command
+    }
+
+    #[cfg_attr(target_os = "windows", allow(unused_variables))]
+    fn program(&self, shell: Option<&ShellConfig>) -> Command {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            CommandLine::Shell(cmd) => {
+                let mut command =
+                    Command::new(shell.map_or("sh".into(), |s| expand_env(&s.program)));
+                if let Some(shell) = shell {
+                    if shell.login {
+                        command.arg("-l");
+                    }
+                    if shell.interactive {
+                        command.arg("-i");
+                    }
+                }
+                command.arg("-c").arg(cmd);
+                command
+            }
+            // cmd.exe has its own quoting rules, so pass the line through untouched
+            // `shell` is ignored here; cmd.exe is the only option
+            #[cfg(target_os = "windows")]
+            CommandLine::Shell(cmd) => {
+                use std::os::windows::process::CommandExt;
+                let mut command = Command::new("cmd");
+                command.arg("/C").raw_arg(cmd);
+                command
+            }
+            // An app bundle isn't executable itself; `open` launches it the way Finder does,
+            // outside the daemon's process tree
+            #[cfg(target_os = "macos")]
+            CommandLine::Exec(program, args) if program.trim_end_matches('/').ends_with(".app") => {
+                let mut command = Command::new("open");
+                command.arg("-a").arg(program).arg("--args").args(args);
+                command
+            }
+            CommandLine::Exec(program, args) => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+            CommandLine::Http { .. } => unreachable!("HTTP requests aren't spawned"),
+        }
+    }
+}
+
+impl std::fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandLine::Shell(cmd) => write!(f, "{}", cmd),
+            CommandLine::Exec(program, args) => {
+                write!(f, "{:?}", program)?;
+                args.iter().try_for_each(|arg| write!(f, " {:?}", arg))
+            }
+            CommandLine::Http { method, url, .. } => write!(f, "{} {}", method, url),
+        }
+    }
+}
+
+/// UDP sockets for Osc actions, one per address family, opened on first use
+struct OscSender {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    // Resolved `host:port` addresses, so a host name isn't looked up on the MIDI thread for
+    // every message. None if it didn't resolve.
+    targets: HashMap<String, Option<SocketAddr>>,
+    dry_run: bool,
+}
+
+impl OscSender {
+    fn new(dry_run: bool) -> Self {
+        Self {
+            v4: None,
+            v6: None,
+            targets: HashMap::new(),
+            dry_run,
+        }
+    }
+
+    // Look up the addresses of the Osc actions among `actions` ahead of their first message
+    fn resolve<'a>(&mut self, actions: impl Iterator<Item = &'a Action>) {
+        if self.dry_run {
+            return;
+        }
+        for action in actions.flat_map(Action::with_nested) {
+            if let Action::Osc { address, .. } = action {
+                self.target(address);
+            }
+        }
+    }
+
+    fn target(&mut self, address: &str) -> Option<SocketAddr> {
+        if let Some(&target) = self.targets.get(address) {
+            return target;
+        }
+        let target = match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(target)) => Some(target),
+            Ok(None) => {
+                warn!("OSC address {} doesn't resolve to anything", address);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to resolve OSC address {}: {}", address, e);
+                None
+            }
+        };
+        self.targets.insert(address.to_string(), target);
+        target
+    }
+
+    fn send(&mut self, address: &str, path: &str, args: Vec<OscType>) {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would send OSC {} {:?} to {}",
+                path, args, address
+            );
+            return;
+        }
+        let Some(target) = self.target(address) else {
+            error!("Failed to send OSC {} to {}: unknown host", path, address);
+            return;
+        };
+        let packet = OscPacket::Message(OscMessage {
+            addr: path.to_string(),
+            args,
+        });
+        let result = encoder::encode(&packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            .and_then(|bytes| {
+                // Bound to the unspecified address of the target's family
+                let (slot, local) = match target {
+                    SocketAddr::V4(_) => {
+                        (&mut self.v4, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+                    }
+                    SocketAddr::V6(_) => {
+                        (&mut self.v6, SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+                    }
+                };
+                let socket = match slot {
+                    Some(socket) => socket,
+                    slot => slot.insert(UdpSocket::bind(local)?),
+                };
+                socket.send_to(&bytes, target).map(drop)
+            });
+        if let Err(e) = result {
+            error!("Failed to send OSC {} to {}: {}", path, address, e);
+        }
+    }
+}
+
+struct QueuedCommand {
+    // Control that produced the command
+    source: (u8, Control),
+    cmd: CommandLine,
+    // A newer command from the same control and action slot replaces a queued one
+    coalesce: Option<usize>,
+    // Dropped while another singleton command from the same control is queued or running
+    singleton: bool,
+    // Shell command run after a non-zero exit, its `{exit_code}` still to be filled in
+    on_error: Option<String>,
+    timeout: Option<Duration>,
+    // Working directory and extra environment for the child, from Command and Exec
+    cwd: Option<PathBuf>,
+    env: HashMap<String, String>,
+}
+
+impl QueuedCommand {
+    // A plain command: no coalescing, timeout, error handler or extra environment
+    fn new(source: (u8, Control), cmd: CommandLine) -> Self {
+        Self {
+            source,
+            cmd,
+            coalesce: None,
+            singleton: false,
+            on_error: None,
+            timeout: None,
+            cwd: None,
+            env: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<QueuedCommand>,
+    // Commands started (or being sent) and not yet reaped, on_error commands included
+    running: usize,
+    // Controls whose singleton command is among them
+    running_singletons: Vec<(u8, Control)>,
+    closed: bool,
+}
+
+// Values of a FaderGroup's members (0 until they first move), and whether a command with
+// them is already waiting for the group to settle
+#[derive(Default)]
+struct GroupState {
+    values: Vec<u16>,
+    pending: bool,
+}
+
+// A started child, waited on by the reaper
+struct RunningCommand {
+    child: Child,
+    job: QueuedCommand,
+    // When to kill it, from the job's timeout
+    deadline: Option<Instant>,
+    // Started as another command's on_error, so its own failure isn't counted or handled
+    handler: bool,
+}
+
+/// Bounded queue of shell commands started by a fixed pool of workers.
+/// A reaper thread waits on every child, so none are left as zombies and a long-running
+/// program doesn't hold up a worker; `push` never blocks.
+#[derive(Clone)]
+struct CommandQueue {
+    shared: Arc<(Mutex<QueueState>, Condvar)>,
+    // Children waiting to be reaped, and a signal for the reaper that there are some
+    children: Arc<(Mutex<Vec<RunningCommand>>, Condvar)>,
+    // Log commands instead of running them
+    dry_run: bool,
+    // Runs Shell command lines instead of `sh -c`
+    shell: Option<Arc<ShellConfig>>,
+    // Commands that couldn't start, exited non-zero or timed out, and failed HTTP requests
+    failed: Arc<AtomicU64>,
+}
+
+impl CommandQueue {
+    fn new(workers: usize, dry_run: bool, shell: Option<ShellConfig>) -> Self {
+        let queue = Self {
+            shared: Arc::new((Mutex::new(QueueState::default()), Condvar::new())),
+            children: Arc::default(),
+            dry_run,
+            shell: shell.map(Arc::new),
+            failed: Arc::default(),
+        };
+        if workers > 0 {
+            let reaper = queue.clone();
+            thread::spawn(move || reaper.reap());
+        }
+        for _ in 0..workers {
+            let worker = queue.clone();
+            thread::spawn(move || worker.work());
+        }
+        queue
+    }
+
+    fn push(&self, job: QueuedCommand) {
+        if self.dry_run {
+            info!("[dry-run] Would run: {}", job.cmd);
+            return;
+        }
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        if job.coalesce.is_some() {
+            if let Some(queued) = state
+                .pending
+                .iter_mut()
+                .find(|q| q.coalesce == job.coalesce && q.source == job.source)
+            {
+                *queued = job;
+                return;
+            }
+        }
+        if job.singleton
+            && (state.running_singletons.contains(&job.source)
+                || state
+                    .pending
+                    .iter()
+                    .any(|q| q.singleton && q.source == job.source))
+        {
+            let (channel, id) = job.source;
+            info!(
+                "Skipping command for ch{} id {}, the previous one is still running: {}",
+                channel, id, job.cmd
+            );
+            return;
+        }
+        if state.pending.len() >= MAX_QUEUED_COMMANDS {
+            warn!("Command queue full, dropping: {}", job.cmd);
+            return;
+        }
+        state.pending.push_back(job);
+        ready.notify_one();
+    }
+
+    // Drop queued commands and wait up to `grace` for running ones to be reaped.
+    // Returns false if some were still running when the grace period ran out.
+    fn shutdown(&self, grace: Duration) -> bool {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.closed = true;
+        state.pending.clear();
+        ready.notify_all();
+        let (state, _) = ready
+            .wait_timeout_while(state, grace, |s| s.running > 0)
+            .unwrap();
+        state.running == 0
+    }
+
+    fn work(&self) {
+        let (lock, ready) = &*self.shared;
+        loop {
+            let job = {
+                let mut state = lock.lock().unwrap();
+                loop {
+                    if state.closed {
+                        return;
+                    }
+                    if let Some(job) = state.pending.pop_front() {
+                        state.running += 1;
+                        if job.singleton {
+                            state.running_singletons.push(job.source);
+                        }
+                        break job;
+                    }
+                    state = ready.wait(state).unwrap();
+                }
+            };
+            if let CommandLine::Http { method, url, body } = &job.cmd {
+                if !Self::request(method, url, body.as_deref(), job.timeout) {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                self.finish(&job);
+            } else {
+                self.start(job, false);
+            }
+        }
+    }
+
+    // Spawn a job's process and hand it to the reaper, so the worker is free again as soon as
+    // it has started
+    fn start(&self, job: QueuedCommand, handler: bool) {
+        let mut command = job.cmd.to_command(self.shell.as_deref());
+        if let Some(cwd) = &job.cwd {
+            command.current_dir(cwd);
+        }
+        match command.envs(&job.env).spawn() {
+            Ok(child) => {
+                let (lock, started) = &*self.children;
+                lock.lock().unwrap().push(RunningCommand {
+                    child,
+                    deadline: job.timeout.map(|timeout| Instant::now() + timeout),
+                    job,
+                    handler,
+                });
+                started.notify_one();
+            }
+            Err(e) => {
+                error!("Failed to spawn command '{}': {}", job.cmd, e);
+                self.fail(job, handler, &SPAWN_FAILED_EXIT_CODE.to_string());
+            }
+        }
+    }
+
+    // Count a job that couldn't start or exited non-zero, and start its on_error command,
+    // which takes over its place among the running commands
+    fn fail(&self, mut job: QueuedCommand, handler: bool, exit_code: &str) {
+        if handler {
+            return self.finish(&job);
+        }
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let Some(on_error) = job.on_error.take() else {
+            return self.finish(&job);
+        };
+        let handler = QueuedCommand {
+            cmd: CommandLine::Shell(on_error.replace("{exit_code}", exit_code)),
+            coalesce: None,
+            ..job
+        };
+        self.start(handler, true);
+    }
+
+    // Free a job's place among the running commands once it's done
+    fn finish(&self, job: &QueuedCommand) {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.running -= 1;
+        if job.singleton {
+            state
+                .running_singletons
+                .retain(|source| *source != job.source);
+        }
+        ready.notify_all();
+    }
+
+    // Wait on every started child, killing those that outlive their timeout, and finish each
+    // one once it has exited
+    fn reap(&self) {
+        let (lock, started) = &*self.children;
+        loop {
+            let mut exited = Vec::new();
+            {
+                let mut children = started
+                    .wait_while(lock.lock().unwrap(), |children| children.is_empty())
+                    .unwrap();
+                let now = Instant::now();
+                let mut i = 0;
+                while i < children.len() {
+                    let running = &mut children[i];
+                    match running.child.try_wait() {
+                        Ok(Some(status)) => exited.push((children.swap_remove(i), Ok(status))),
+                        Err(e) => exited.push((children.swap_remove(i), Err(e))),
+                        Ok(None) => {
+                            if running.deadline.is_some_and(|deadline| now >= deadline) {
+                                running.kill();
+                            }
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            for (running, result) in exited {
+                self.exited(running, result);
+            }
+            thread::sleep(REAP_INTERVAL);
+        }
+    }
+
+    // Log how a reaped command went and finish it, or run its on_error command if it failed
+    fn exited(&self, running: RunningCommand, result: io::Result<ExitStatus>) {
+        let RunningCommand { job, handler, .. } = running;
+        let (channel, id) = job.source;
+        match result {
+            Ok(status) => {
+                debug!(
+                    channel = channel,
+                    id:% = id,
+                    result:% = status;
+                    "Command for ch{} id {} finished ({}): {}",
+                    channel,
+                    id,
+                    status,
+                    job.cmd
+                );
+                if status.success() {
+                    self.finish(&job);
+                } else {
+                    // No code when it was killed by a signal, e.g. after its timeout
+                    let exit_code = status.code().map_or(String::new(), |code| code.to_string());
+                    self.fail(job, handler, &exit_code);
+                }
+            }
+            Err(e) => {
+                error!("Failed to wait for command '{}': {}", job.cmd, e);
+                if !handler {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                self.finish(&job);
+            }
+        }
+    }
+
+    // Send an Http action's request, logging failures and non-2xx responses. Returns whether
+    // it got a 2xx response.
+    fn request(method: &str, url: &str, body: Option<&str>, timeout: Option<Duration>) -> bool {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout.unwrap_or(HTTP_TIMEOUT))
+            .build();
+        let request = agent.request(method, url);
+        let result = match body {
+            Some(body) => request.send_string(body),
+            None => request.call(),
+        };
+        match result {
+            Ok(response) if (200..300).contains(&response.status()) => {
+                debug!(
+                    result = response.status();
+                    "{} {} -> {}",
+                    method,
+                    url,
+                    response.status()
+                );
+                return true;
+            }
+            Ok(response) | Err(ureq::Error::Status(_, response)) => warn!(
+                "{} {} returned {} {}",
+                method,
+                url,
+                response.status(),
+                response.status_text()
+            ),
+            Err(e) => error!("{} {} failed: {}", method, url, e),
+        }
+        false
+    }
+}
+
+impl RunningCommand {
+    // Kill a command that outlived its timeout; the next sweep reaps it
+    fn kill(&mut self) {
+        let (channel, id) = self.job.source;
+        warn!(
+            "Killing command for ch{} id {} after {}ms: {}",
+            channel,
+            id,
+            self.job.timeout.unwrap_or_default().as_millis(),
+            self.job.cmd
+        );
+        if let Err(e) = kill_group(&mut self.child) {
+            error!("Failed to kill command '{}': {}", self.job.cmd, e);
+        }
+        self.deadline = None;
+    }
+}
+
+// Kill a child along with everything it started, e.g. the programs of a `sh -c` line. On Unix
+// each child leads a process group of its own (see `to_command`), so signal the whole group.
+#[cfg(unix)]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    // SAFETY: kill takes no pointers, it only sends a signal
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        child.kill()
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+// The parts of a config an included file can add to
+#[derive(Deserialize)]
+struct IncludedConfig {
+    #[serde(default)]
+    mappings: HashMap<String, Mapping>,
+    #[serde(default)]
+    layers: HashMap<String, HashMap<String, Mapping>>,
+    #[serde(default)]
+    sysex: HashMap<String, Action>,
+}
+
+// Built-in device profiles, by the name `profile` refers to them with
+const PROFILES: &[(&str, &str)] = &[
+    ("mpd218", include_str!("profiles/mpd218.toml")),
+    ("nanokontrol2", include_str!("profiles/nanokontrol2.toml")),
+];
+
+// Names for a controller's controls, each standing for a mapping key such as 41 or "36-51"
+#[derive(Deserialize)]
+struct Profile {
+    controls: HashMap<String, ProfileControl>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProfileControl {
+    Id(u8),
+    Key(String),
+}
+
+impl ProfileControl {
+    fn key(&self) -> String {
+        match self {
+            ProfileControl::Id(id) => id.to_string(),
+            ProfileControl::Key(key) => key.clone(),
+        }
+    }
+}
+
+// A built-in profile by name, otherwise a profile file relative to the config's directory
+fn load_profile(name: &str, config_path: &Path) -> Result<Profile> {
+    if let Some((_, text)) = PROFILES.iter().find(|(builtin, _)| *builtin == name) {
+        return toml::from_str(text).map_err(|e| MidiActionError::ConfigParse {
+            path: PathBuf::from(name),
+            error: e.into(),
+        });
+    }
+    let path = config_path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(expand_env(name));
+    if !path.is_file() {
+        let builtins: Vec<&str> = PROFILES.iter().map(|(builtin, _)| *builtin).collect();
+        return Err(MidiActionError::InvalidConfig(vec![format!(
+            "profile: '{}' is neither a built-in profile ({}) nor a file",
+            name,
+            builtins.join(", ")
+        )]));
+    }
+    parse_config_file(&path)
+}
+
+// Replace the mapping keys that are control names with the ids the profile gives them
+fn apply_profile(config: &mut MidiConfig, profile: &Profile, name: &str) -> Result<()> {
+    let mut problems = Vec::new();
+    let mut resolve = |label: &str, mappings: &mut HashMap<String, Mapping>| {
+        let (named, ids): (Vec<_>, Vec<_>) = mappings
+            .drain()
+            .partition(|(key, _)| parse_mapping_key(key).is_err());
+        mappings.extend(ids);
+        for (key, mapping) in named {
+            let Some(control) = profile.controls.get(&key) else {
+                problems.push(format!(
+                    "Mapping '{}{}': neither an id nor a control of profile '{}'",
+                    label, key, name
+                ));
+                continue;
+            };
+            match mappings.entry(control.key()) {
+                Entry::Occupied(entry) => problems.push(format!(
+                    "Mapping '{}{}': profile '{}' names {}, which is mapped already",
+                    label,
+                    key,
+                    name,
+                    entry.key()
+                )),
+                Entry::Vacant(entry) => {
+                    entry.insert(mapping);
+                }
+            }
+        }
+    };
+    resolve("", &mut config.mappings);
+    for (layer, mappings) in &mut config.layers {
+        resolve(&format!("{}.", layer), mappings);
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        problems.sort();
+        Err(MidiActionError::InvalidConfig(problems))
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<MidiConfig> {
+    let mut config: MidiConfig = parse_config_file(path)?;
+    for include in include_paths(path, &config) {
+        let included: IncludedConfig = parse_config_file(&include)?;
+        merge_included(
+            &mut config.mappings,
+            included.mappings,
+            &include,
+            "mappings",
+        );
+        for (name, mappings) in included.layers {
+            let section = format!("layers.{}", name);
+            let layer = config.layers.entry(name).or_default();
+            merge_included(layer, mappings, &include, &section);
+        }
+        merge_included(&mut config.sysex, included.sysex, &include, "sysex");
+    }
+    if let Some(name) = config.profile.clone() {
+        let profile = load_profile(&name, path)?;
+        apply_profile(&mut config, &profile, &name)?;
+    }
+    Ok(config)
+}
+
+/// The files `config`, loaded from `path`, includes. Relative paths are resolved against its directory.
+pub fn include_paths(path: &Path, config: &MidiConfig) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    config
+        .include
+        .iter()
+        .map(|include| dir.join(expand_env(include)))
+        .collect()
+}
+
+fn merge_included<V>(
+    into: &mut HashMap<String, V>,
+    from: HashMap<String, V>,
+    source: &Path,
+    section: &str,
+) {
+    for (key, value) in from {
+        if into.insert(key.clone(), value).is_some() {
+            debug!("{} overrides [{}] {}", source.display(), section, key);
+        }
+    }
+}
+
+/// The config path that stands for standard input, as in `--config -`
+pub const STDIN_CONFIG: &str = "-";
+
+fn parse_config_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let config_str = if path == Path::new(STDIN_CONFIG) {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path).map_err(|_| MidiActionError::ConfigNotFound(path.to_path_buf()))?
+    };
+    let parse_error = |error| MidiActionError::ConfigParse {
+        path: path.to_path_buf(),
+        error,
+    };
+    // Anything that isn't JSON or YAML, stdin included, is read as TOML
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&config_str).map_err(|e| parse_error(e.into())),
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(&config_str).map_err(|e| parse_error(e.into()))
+        }
+        _ => toml::from_str(&config_str).map_err(|e| parse_error(e.into())),
+    }
+}
+
+// Every problem in the config is collected so they can all be fixed in one go
+fn build_layers(config: &MidiConfig) -> Result<Layers> {
+    let mut problems = Vec::new();
+    if let Err(e) = DeviceMatcher::new(config) {
+        problems.push(format!("device_name: {}", e));
+    }
+    if config
+        .thru
+        .as_ref()
+        .is_some_and(|t| t.port.trim().is_empty())
+    {
+        problems.push("thru: port is empty".to_string());
+    }
+    if config
+        .shell
+        .as_ref()
+        .is_some_and(|s| s.program.trim().is_empty())
+    {
+        problems.push("shell: program is empty".to_string());
+    }
+    let mut layer_names: Vec<&str> = config.layers.keys().map(String::as_str).collect();
+    layer_names.push(DEFAULT_LAYER);
+    if config.layers.contains_key(DEFAULT_LAYER) {
+        problems.push(format!(
+            "Layer '{}' is reserved for the top-level mappings",
+            DEFAULT_LAYER
+        ));
+    }
+
+    let mut named = HashMap::new();
+    for (name, mappings) in &config.layers {
+        let label = format!("{}.", name);
+        named.insert(
+            name.clone(),
+            build_mappings(&label, mappings, &layer_names, &mut problems),
+        );
+    }
+    let base = build_mappings("", &config.mappings, &layer_names, &mut problems);
+    let fallback = config
+        .default
+        .as_ref()
+        .filter(|fallback| fallback.enabled.unwrap_or(true));
+    if let Some(fallback) = fallback {
+        validate_mapping("default", fallback, &layer_names, &mut problems);
+        if let Action::Linear {
+            high_res: Some(_), ..
+        } = fallback.action
+        {
+            problems.push("Mapping 'default': high_res needs a mapping of its own".to_string());
+        }
+        if let Action::FaderGroup { .. } = fallback.action {
+            problems.push("Mapping 'default': FaderGroup needs a range of ids".to_string());
+        }
+        if let Action::ClockDivider { .. } = fallback.action {
+            problems.push(
+                "Mapping 'default': ClockDivider only works on the \"clock\" key".to_string(),
+            );
+        }
+    }
+    let mut sysex = Vec::new();
+    for (key, action) in &config.sysex {
+        let id = format!("sysex.{}", key);
+        match parse_sysex_prefix(key) {
+            Ok(prefix) => {
+                validate_step(&id, action, "sysex actions", &layer_names, &mut problems);
+                sysex.push((prefix, action.clone()));
+            }
+            Err(e) => problems.push(format!("Mapping '{}': {}", id, e)),
+        }
+    }
+    sysex.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+    let mut high_res = Vec::new();
+    for mapping in base
+        .values()
+        .chain(named.values().flat_map(HashMap::values))
+    {
+        if let Action::Linear {
+            high_res: Some(pair),
+            ..
+        } = mapping.action
+        {
+            if !high_res.contains(&pair) {
+                high_res.push(pair);
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(Layers {
+            base,
+            named,
+            fallback: fallback.cloned(),
+            sysex,
+            high_res,
+        })
+    } else {
+        problems.sort();
+        Err(MidiActionError::InvalidConfig(problems))
+    }
+}
+
+// A SysEx pattern is hex bytes separated by spaces, starting with F0
+fn parse_sysex_prefix(key: &str) -> std::result::Result<Vec<u8>, String> {
+    let bytes: Vec<u8> = key
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| "SysEx patterns are hex bytes separated by spaces, e.g. \"F0 00 20 6B\"")?;
+    if bytes.first() != Some(&SYSEX_START) {
+        return Err("SysEx patterns must start with F0".to_string());
+    }
+    Ok(bytes)
+}
+
+// Create runtime mappings keyed by (channel, control). `label` prefixes
+// mapping ids in problem reports so layer mappings can be told apart.
+fn build_mappings(
+    label: &str,
+    config: &HashMap<String, Mapping>,
+    layer_names: &[&str],
+    problems: &mut Vec<String>,
+) -> Mappings {
+    // Every id a key covers, narrowest keys first so they win over ranges that overlap them
+    let mut expanded = Vec::new();
+    for (k, mapping) in config {
+        if !mapping.enabled.unwrap_or(true) {
+            continue;
+        }
+        let k = format!("{}{}", label, k);
+        let before = problems.len();
+        let ids = parse_mapping_key(&k[label.len()..]);
+        if let Err(e) = &ids {
+            problems.push(format!("Mapping '{}': {}", k, e));
+        }
+        // Continuous sources have no press to trigger anything else
+        if let Ok([control @ (Control::PitchBend | Control::Pressure | Control::NotePressure(_))]) =
+            ids.as_deref()
+        {
+            let continuous = |a: &Action| {
+                matches!(
+                    a,
+                    Action::Linear { .. }
+                        | Action::Volume { .. }
+                        | Action::Steps { .. }
+                        | Action::FaderGroup { .. }
+                )
+            };
+            let linear = match &mapping.action {
+                Action::Multi { actions } => actions.iter().all(continuous),
+                action => continuous(action),
+            };
+            if !linear {
+                problems.push(format!(
+                    "Mapping '{}': {} can only drive Linear, Volume, Steps and FaderGroup actions",
+                    k, control
+                ));
+            }
+        }
+        // Clock pulses have no value or release, and dividers count nothing else
+        let clock = matches!(ids.as_deref(), Ok([Control::Clock]));
+        let divider = |a: &Action| matches!(a, Action::ClockDivider { .. });
+        let (all_dividers, any_divider) = match &mapping.action {
+            Action::Multi { actions } => (actions.iter().all(divider), actions.iter().any(divider)),
+            action => (divider(action), divider(action)),
+        };
+        if clock && !all_dividers {
+            problems.push(format!(
+                "Mapping '{}': clock can only drive ClockDivider actions",
+                k
+            ));
+        } else if !clock && any_divider {
+            problems.push(format!(
+                "Mapping '{}': ClockDivider only works on the \"clock\" key",
+                k
+            ));
+        }
+        if clock && mapping.channel.is_some() {
+            problems.push(format!("Mapping '{}': clock messages have no channel", k));
+        }
+        if let Action::Linear {
+            high_res: Some(HighRes { msb, .. }),
+            ..
+        } = mapping.action
+        {
+            if !matches!(ids.as_deref(), Ok([Control::Id(id)]) if *id == msb) {
+                problems.push(format!(
+                    "Mapping '{}': high_res msb {} must be the mapping's id",
+                    k, msb
+                ));
+            }
+        }
+        validate_mapping(&k, mapping, layer_names, problems);
+        if let (Ok(ids), true) = (ids, problems.len() == before) {
+            let mut mapping = mapping.clone();
+            let actions = match &mut mapping.action {
+                Action::Multi { actions } => actions.as_mut_slice(),
+                action => std::slice::from_mut(action),
+            };
+            for action in actions {
+                if let Action::FaderGroup { members, .. } = action {
+                    members.clone_from(&ids);
+                }
+            }
+            expanded.push((k, ids, mapping));
+        }
+    }
+    expanded.sort_by(|(a_key, a, _), (b_key, b, _)| a.len().cmp(&b.len()).then(a_key.cmp(b_key)));
+
+    let mut mappings = HashMap::new();
+    // The key behind each entry, and how many ids it covers
+    let mut owners = HashMap::new();
+    for (k, ids, mapping) in &expanded {
+        for &id in ids {
+            // Lookups try the message's channel first, so a range restricted to it mustn't
+            // shadow a narrower key for any channel
+            if mapping.channel.is_some()
+                && owners
+                    .get(&(None, id))
+                    .is_some_and(|(_, width)| *width < ids.len())
+            {
+                continue;
+            }
+            if let Entry::Vacant(entry) = mappings.entry((mapping.channel, id)) {
+                owners.insert((mapping.channel, id), (k.as_str(), ids.len()));
+                entry.insert(mapping.clone());
+            }
+        }
+    }
+    // A FaderGroup only knows its members' values from the messages it gets, so none of them
+    // may go to another mapping
+    let group = |a: &Action| matches!(a, Action::FaderGroup { .. });
+    for (k, ids, mapping) in &expanded {
+        let grouped = match &mapping.action {
+            Action::Multi { actions } => actions.iter().any(group),
+            action => group(action),
+        };
+        if !grouped {
+            continue;
+        }
+        let mut shadowing: Vec<_> = owners
+            .iter()
+            .filter(|((channel, id), (owner, _))| {
+                owner != k
+                    && ids.contains(id)
+                    && (mapping.channel.is_none() || *channel == mapping.channel)
+            })
+            .map(|(_, (owner, _))| *owner)
+            .collect();
+        shadowing.sort();
+        shadowing.dedup();
+        for owner in shadowing {
+            problems.push(format!(
+                "Mapping '{}': overlaps FaderGroup '{}', which needs every message from its members",
+                owner, k
+            ));
+        }
+    }
+    mappings
+}
+
+// A mapping key is a single control, an inclusive range of ids like "36-51", or "*" for
+// every id from 0 to 127
+fn parse_mapping_key(key: &str) -> std::result::Result<Vec<Control>, String> {
+    if key == "*" {
+        return Ok((0..=127).map(Control::Id).collect());
+    }
+    let Some((first, last)) = key.split_once('-') else {
+        return key.parse().map(|id| vec![id]);
+    };
+    match (first.trim().parse::<u8>(), last.trim().parse::<u8>()) {
+        (Ok(first), Ok(last)) if first <= last => Ok((first..=last).map(Control::Id).collect()),
+        (Ok(_), Ok(_)) => Err(format!("range '{}' ends before it starts", key)),
+        _ => Err(format!("range '{}' must be two ids, e.g. \"36-51\"", key)),
+    }
+}
+
+fn validate_mapping(id: &str, mapping: &Mapping, layer_names: &[&str], problems: &mut Vec<String>) {
+    if let Some(channel) = mapping.channel {
+        if channel > 15 {
+            problems.push(format!(
+                "Mapping '{}': channel {} out of range (0-15)",
+                id, channel
+            ));
+        }
+    }
+    if let Some(Curve::Gamma(gamma)) = mapping.curve {
+        if !(gamma.is_finite() && gamma > 0.0) {
+            problems.push(format!(
+                "Mapping '{}': curve gamma must be a positive number (got {})",
+                id, gamma
+            ));
+        }
+    }
+    if let Some(velocity) = mapping.min_velocity {
+        if velocity > 127 {
+            problems.push(format!(
+                "Mapping '{}': min_velocity {} out of range (0-127)",
+                id, velocity
+            ));
+        }
+    }
+    validate_action(id, &mapping.action, layer_names, problems);
+    if let (
+        Action::Toggle {
+            mode: ToggleMode::Momentary,
+            ..
+        },
+        Some(_),
+    ) = (&mapping.action, &mapping.on_release)
+    {
+        problems.push(format!(
+            "Mapping '{}': a momentary Toggle already runs off_cmd on release, so on_release isn't allowed",
+            id
+        ));
+    }
+    if let Some(action) = &mapping.on_release {
+        validate_action(&format!("{}.on_release", id), action, layer_names, problems);
+    }
+}
+
+fn validate_action(id: &str, action: &Action, layer_names: &[&str], problems: &mut Vec<String>) {
+    for code in action.key_codes() {
+        if !is_known_key(code) {
+            problems.push(format!("Mapping '{}': unknown key code '{}'", id, code));
+        }
+    }
+    let mut require_command = |field: &str, cmd: &str| {
+        if cmd.trim().is_empty() {
+            problems.push(format!("Mapping '{}': {} is empty", id, field));
+        }
+    };
+    match action {
+        Action::Chord { codes } if codes.is_empty() => {
+            problems.push(format!("Mapping '{}': chord has no keys", id))
+        }
+        Action::Command {
+            cmd, cwd, on_error, ..
+        }
+        | Action::Exec {
+            program: cmd,
+            cwd,
+            on_error,
+            ..
+        } => {
+            let field = if let Action::Command { .. } = action {
+                "cmd"
+            } else {
+                "program"
+            };
+            require_command(field, cmd);
+            if let Some(on_error) = on_error {
+                require_command("on_error", on_error);
+            }
+            if let Some(cwd) = cwd {
+                if !Path::new(&expand_env(cwd)).is_dir() {
+                    problems.push(format!(
+                        "Mapping '{}': cwd '{}' is not a directory",
+                        id, cwd
+                    ));
+                }
+            }
+        }
+        Action::FaderGroup { template, .. } => require_command("template", template),
+        Action::Steps { commands } if commands.is_empty() => {
+            problems.push(format!("Mapping '{}': steps has no commands", id))
+        }
+        Action::Steps { commands } => {
+            for (i, cmd) in commands.iter().enumerate() {
+                require_command(&format!("commands[{}]", i), cmd);
+            }
+        }
+        #[cfg(target_os = "windows")]
+        Action::Volume { .. } if !on_path("nircmd.exe") => problems.push(format!(
+            "Mapping '{}': Volume needs NirCmd on Windows, and nircmd.exe isn't on the PATH",
+            id
+        )),
+        Action::Http { url, method, .. } => {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!(
+                    "Mapping '{}': URL '{}' must start with http:// or https://",
+                    id, url
+                ));
+            }
+            if let Some(method) = method {
+                if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphabetic()) {
+                    problems.push(format!(
+                        "Mapping '{}': invalid HTTP method '{}'",
+                        id, method
+                    ));
+                }
+            }
+        }
+        Action::Osc { address, path, .. } => {
+            if !address.contains(':') {
+                problems.push(format!(
+                    "Mapping '{}': OSC address '{}' needs a port (host:port)",
+                    id, address
+                ));
+            }
+            if !path.starts_with('/') {
+                problems.push(format!(
+                    "Mapping '{}': OSC path '{}' must start with '/'",
+                    id, path
+                ));
+            }
+        }
+        Action::Relative {
+            inc_cmd,
+            dec_cmd,
+            cmd,
+            acceleration,
+            ..
+        } => {
+            match (cmd, inc_cmd, dec_cmd) {
+                (Some(cmd), None, None) => require_command("cmd", cmd),
+                (None, Some(inc_cmd), Some(dec_cmd)) => {
+                    require_command("inc_cmd", inc_cmd);
+                    require_command("dec_cmd", dec_cmd);
+                }
+                _ => problems.push(format!(
+                    "Mapping '{}': Relative needs either cmd or both inc_cmd and dec_cmd",
+                    id
+                )),
+            }
+            if let Some(Acceleration { factor, .. }) = acceleration {
+                if !(factor.is_finite() && *factor >= 1.0) {
+                    problems.push(format!(
+                        "Mapping '{}': acceleration factor must be at least 1 (got {})",
+                        id, factor
+                    ));
+                }
+            }
+        }
+        Action::Toggle {
+            on_cmd, off_cmd, ..
+        } => {
+            require_command("on_cmd", on_cmd);
+            require_command("off_cmd", off_cmd);
+        }
+        Action::KeyRepeat {
+            rate_ms,
+            pressure_rate_ms,
+            ..
+        } => {
+            if *rate_ms == Some(0) {
+                problems.push(format!("Mapping '{}': rate_ms must be at least 1", id));
+            }
+            if *pressure_rate_ms == Some(0) {
+                problems.push(format!(
+                    "Mapping '{}': pressure_rate_ms must be at least 1",
+                    id
+                ));
+            }
+        }
+        Action::TapTempo { cmd, taps, .. } => {
+            require_command("cmd", cmd);
+            if *taps == Some(0) {
+                problems.push(format!("Mapping '{}': taps must be at least 1", id));
+            }
+        }
+        Action::Linear {
+            template,
+            min,
+            max,
+            precision,
+            threshold,
+            dedup,
+            center,
+            high_res,
+            smoothing,
+            ..
+        } => {
+            if smoothing.is_some_and(|s| !(0.0..1.0).contains(&s)) {
+                problems.push(format!(
+                    "Mapping '{}': smoothing must be at least 0 and below 1",
+                    id
+                ));
+            }
+            if let Some(center) = center {
+                let (min, max) = (min.unwrap_or(0.0), max.unwrap_or(100.0));
+                if *center < min.min(max) || *center > min.max(max) {
+                    problems.push(format!(
+                        "Mapping '{}': center {} is outside {}..{}",
+                        id, center, min, max
+                    ));
+                }
+            }
+            if let Some(HighRes { msb, lsb }) = high_res {
+                if *msb > 127 || *lsb > 127 || msb == lsb {
+                    problems.push(format!(
+                        "Mapping '{}': high_res needs two different CCs from 0 to 127",
+                        id
+                    ));
+                }
+            }
+            if !template.contains("{}") {
+                problems.push(format!(
+                    "Mapping '{}': Linear template has no '{{}}' placeholder",
+                    id
+                ));
+            }
+            if precision.is_none()
+                && [min, max]
+                    .iter()
+                    .any(|v| v.is_some_and(|v| v.fract() != 0.0))
+            {
+                problems.push(format!(
+                    "Mapping '{}': min and max must be whole numbers unless precision is set",
+                    id
+                ));
+            }
+            if precision.is_some() && threshold.is_some() {
+                problems.push(format!(
+                    "Mapping '{}': threshold can't be combined with precision",
+                    id
+                ));
+            }
+            if *dedup == Some(false) && threshold.is_some() {
+                problems.push(format!(
+                    "Mapping '{}': threshold can't be combined with dedup = false",
+                    id
+                ));
+            }
+        }
+        Action::Layer { name } | Action::Modifier { layer: name }
+            if !layer_names.contains(&name.as_str()) =>
+        {
+            problems.push(format!("Mapping '{}': unknown layer '{}'", id, name))
+        }
+        Action::Multi { actions } => {
+            if actions.is_empty() {
+                problems.push(format!("Mapping '{}': multi has no actions", id));
+            }
+            for (i, action) in actions.iter().enumerate() {
+                let action_id = format!("{}.actions[{}]", id, i);
+                if let Action::Multi { .. } = action {
+                    problems.push(format!("Mapping '{}': multis can't be nested", action_id));
+                } else if let Action::Press { .. } = action {
+                    // Only a mapping's own action sees the release
+                    problems.push(format!(
+                        "Mapping '{}': press can't be part of a multi",
+                        action_id
+                    ));
+                } else if let Action::Toggle {
+                    mode: ToggleMode::Momentary,
+                    ..
+                } = action
+                {
+                    // Same for the release that would run off_cmd
+                    problems.push(format!(
+                        "Mapping '{}': a momentary toggle can't be part of a multi",
+                        action_id
+                    ));
+                } else if let Action::Linear {
+                    high_res: Some(_), ..
+                } = action
+                {
+                    // The MSB alone would starve the other actions
+                    problems.push(format!(
+                        "Mapping '{}': a high_res Linear can't be part of a multi",
+                        action_id
+                    ));
+                } else {
+                    validate_action(&action_id, action, layer_names, problems);
+                }
+            }
+        }
+        Action::Sequence { steps, .. } => {
+            if steps.is_empty() {
+                problems.push(format!("Mapping '{}': sequence has no steps", id));
+            }
+            for (i, step) in steps.iter().enumerate() {
+                let step_id = format!("{}.steps[{}]", id, i);
+                if let Action::Sequence { .. } = step {
+                    problems.push(format!("Mapping '{}': sequences can't be nested", step_id));
+                } else {
+                    validate_step(&step_id, step, "sequence steps", layer_names, problems);
+                }
+            }
+        }
+        Action::Conditional {
+            then, otherwise, ..
+        } => {
+            let branches = [("then", Some(then)), ("else", otherwise.as_ref())];
+            for (name, branch) in branches {
+                if let Some(branch) = branch {
+                    let branch_id = format!("{}.{}", id, name);
+                    validate_step(
+                        &branch_id,
+                        branch,
+                        "conditional branches",
+                        layer_names,
+                        problems,
+                    );
+                }
+            }
+        }
+        Action::Press { short, long, .. } => {
+            for (name, branch) in [("short", short), ("long", long)] {
+                let branch_id = format!("{}.{}", id, name);
+                validate_step(&branch_id, branch, "press actions", layer_names, problems);
+            }
+        }
+        Action::ClockDivider { ticks, action } => {
+            if *ticks == 0 {
+                problems.push(format!("Mapping '{}': ticks must be at least 1", id));
+            }
+            let action_id = format!("{}.action", id);
+            validate_step(
+                &action_id,
+                action,
+                "clock divider actions",
+                layer_names,
+                problems,
+            );
+        }
+        Action::VelocityBands { bands } => {
+            if bands.is_empty() {
+                problems.push(format!("Mapping '{}': velocity bands has no bands", id));
+            }
+            for (i, (min, action)) in bands.iter().enumerate() {
+                let band_id = format!("{}.bands[{}]", id, i);
+                if *min > 127 {
+                    problems.push(format!(
+                        "Mapping '{}': threshold {} is above 127",
+                        band_id, min
+                    ));
+                } else if bands[..i].iter().any(|(other, _)| other == min) {
+                    problems.push(format!(
+                        "Mapping '{}': another band already starts at {}",
+                        band_id, min
+                    ));
+                }
+                validate_step(&band_id, action, "velocity bands", layer_names, problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Sequence steps and Conditional/Press branches run through `run_step`, so only one-shot
+// actions make sense there
+fn validate_step(
+    id: &str,
+    step: &Action,
+    what: &str,
+    layer_names: &[&str],
+    problems: &mut Vec<String>,
+) {
+    match step {
+        // Steps have no press of their own to toggle on
+        Action::Key { latch: true, .. } => {
+            problems.push(format!("Mapping '{}': latch can't be used in {}", id, what))
+        }
+        Action::Key { .. }
+        | Action::Chord { .. }
+        | Action::Command { .. }
+        | Action::Exec { .. }
+        | Action::Http { .. }
+        | Action::Scroll { .. }
+        | Action::MouseMove { .. } => validate_action(id, step, layer_names, problems),
+        _ => problems.push(format!(
+            "Mapping '{}': only Key, Chord, Command, Exec, Http, Scroll and MouseMove can be {}",
+            id, what
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_known_key(code: &str) -> bool {
+    code.parse::<EvdevKey>().is_ok()
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn is_known_key(code: &str) -> bool {
+    string_to_enigo_key(code).is_some()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn is_known_key(_code: &str) -> bool {
+    true
+}
+
+// Fill in the message that triggered a command: `{value}` (velocity / CC value), `{id}`, `{channel}`
+fn expand_placeholders(template: &str, channel: u8, id: Control, value: u16) -> String {
+    template
+        .replace("{value}", &value.to_string())
+        .replace("{id}", &id.to_string())
+        .replace("{channel}", &channel.to_string())
+}
+
+// Whether `program` is in one of the PATH directories
+#[cfg(target_os = "windows")]
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+// Expand a leading `~` and `$VAR` / `${VAR}` from the environment, the way a shell would.
+// Unset variables are left as written.
+pub fn expand_env(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    if rest == "~" || rest.starts_with("~/") {
+        if let Some(home) = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+            out.push_str(&home.to_string_lossy());
+            rest = &rest[1..];
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        match env::var(name) {
+            Ok(value) if valid => {
+                out.push_str(&value);
+                rest = &after[len..];
+            }
+            _ => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Map a MIDI value in 0..=input_max onto min..=max. A reversed range is treated as if it were swapped.
+fn scale_linear(input: u16, input_max: u16, min: i64, max: i64, invert: bool) -> i64 {
+    let (lo, hi) = if min > max { (max, min) } else { (min, max) };
+    let mut fraction = input.min(input_max) as f64 / input_max as f64;
+    if invert {
+        fraction = 1.0 - fraction;
+    }
+    lo + (fraction * (hi - lo) as f64) as i64
+}
+
+// Scale the halves of the control's travel below and above its midpoint (64, or 8192 at
+// 14 bits) into min..center and center..max, for bipolar controls such as pan knobs
+fn scale_centered(
+    input: u16,
+    input_max: u16,
+    min: f64,
+    center: f64,
+    max: f64,
+    invert: bool,
+) -> f64 {
+    let (lo, hi) = if min > max { (max, min) } else { (min, max) };
+    // Inverting swaps the ends rather than the input, so the midpoint stays on `center`
+    let (start, end) = if invert { (hi, lo) } else { (lo, hi) };
+    let input = input.min(input_max);
+    let mid = input_max.div_ceil(2);
+    if input < mid {
+        start + (center - start) * input as f64 / mid as f64
+    } else {
+        center + (end - center) * (input - mid) as f64 / (input_max - mid) as f64
+    }
+}
+
+// Scale a Linear's input into its template and queue the command, unless dedup or the
+// threshold hold it back
+fn send_linear(
+    action: &Action,
+    key: ActionKey,
+    input: u16,
+    input_max: u16,
+    last_knob_vals: &Mutex<HashMap<ActionKey, i64>>,
+    last_linear_texts: &Mutex<HashMap<ActionKey, String>>,
+    commands: &CommandQueue,
+) {
+    let Action::Linear {
+        template,
+        min,
+        max,
+        invert,
+        precision,
+        threshold,
+        dedup,
+        center,
+        ..
+    } = action
+    else {
+        return;
+    };
+    let (channel, id, index) = key;
+    let (min, max) = (min.unwrap_or(0.0), max.unwrap_or(100.0));
+    let exact = || match center {
+        Some(center) => scale_centered(input, input_max, min, *center, max, *invert),
+        None => scale_linear_f64(input, input_max, min, max, *invert),
+    };
+    // Validation guarantees whole numbers when there's no precision
+    let whole = || match center {
+        Some(_) => exact().round() as i64,
+        None => scale_linear(input, input_max, min as i64, max as i64, *invert),
+    };
+    let dedup = dedup.unwrap_or(true);
+    let text = if !dedup {
+        // Every message counts, so there's nothing to compare against
+        Some(match precision {
+            Some(precision) => format!("{:.*}", precision, exact()),
+            None => whole().to_string(),
+        })
+    } else if let Some(precision) = precision {
+        // Deduplicated on the text, so only visible changes run the command
+        let text = format!("{:.*}", precision, exact());
+        let mut cache = last_linear_texts.lock().unwrap();
+        (cache.get(&key) != Some(&text)).then(|| {
+            cache.insert(key, text.clone());
+            text
+        })
+    } else {
+        let mut cache = last_knob_vals.lock().unwrap();
+        let (min, max) = (min as i64, max as i64);
+        let value = whole();
+
+        // The ends of the range always get through so a jittery fader can reach them
+        let moved = match cache.get(&key) {
+            Some(last) if value == min || value == max => *last != value,
+            Some(last) => (value - last).abs() >= threshold.unwrap_or(1),
+            None => true,
+        };
+        moved.then(|| {
+            cache.insert(key, value);
+            value.to_string()
+        })
+    };
+    if let Some(text) = text {
+        let final_cmd = expand_placeholders(&template.replace("{}", &text), channel, id, input);
+        // Only the latest position matters if the fader outruns the workers,
+        // unless the consumer wants the whole stream
+        commands.push(QueuedCommand {
+            coalesce: dedup.then_some(index),
+            ..QueuedCommand::new((channel, id), CommandLine::Shell(final_cmd))
+        });
+    }
+}
+
+// Exponential moving average of a smoothed Linear's input, with the latest reading.
+// `generation` counts readings so the settle step can tell whether the control moved
+// while it waited.
+#[derive(Default)]
+struct Smoothed {
+    value: f64,
+    reading: u16,
+    // Which way the reading last moved, and whether the move before went the same way
+    rising: Option<bool>,
+    steady: bool,
+    generation: u64,
+    settling: bool,
+}
+
+impl Smoothed {
+    // Take in a reading and return the smoothed value. The ends of the range go straight
+    // through, since a fader parked there sends nothing more to converge on.
+    fn update(&mut self, input: u16, input_max: u16, smoothing: f64) -> u16 {
+        if self.generation == 0 || input == 0 || input == input_max {
+            self.value = input as f64;
+        } else {
+            self.value = self.value * smoothing + input as f64 * (1.0 - smoothing);
+        }
+        if self.generation > 0 && input != self.reading {
+            let rising = input > self.reading;
+            self.steady = self.rising == Some(rising);
+            self.rising = Some(rising);
+        }
+        self.reading = input;
+        self.generation += 1;
+        self.value.round() as u16
+    }
+
+    // Whether the average was left behind by a real move rather than jitter: the last
+    // readings kept going one way, or it trails by more than the deadband
+    fn lagging(&self, input_max: u16) -> bool {
+        let lag = (self.reading as f64 - self.value).abs();
+        self.value.round() as u16 != self.reading
+            && (self.steady || lag > input_max as f64 * SMOOTHING_DEADBAND)
+    }
+}
+
+// A smoothed Linear waiting to come to rest, so the reading the average still trails can be
+// sent once it has been still for SMOOTHING_SETTLE
+struct SmoothingSettle {
+    action: Action,
+    key: ActionKey,
+    layer: String,
+    input_max: u16,
+    // The reading it last saw the control at
+    generation: u64,
+    states: Arc<Mutex<HashMap<ActionKey, Smoothed>>>,
+    pickup: Arc<Mutex<Pickup>>,
+    last_knob_vals: Arc<Mutex<HashMap<ActionKey, i64>>>,
+    last_linear_texts: Arc<Mutex<HashMap<ActionKey, String>>>,
+    commands: CommandQueue,
+    clock: Arc<dyn Clock>,
+}
+
+impl SmoothingSettle {
+    fn schedule(self) {
+        let clock = Arc::clone(&self.clock);
+        clock.after(SMOOTHING_SETTLE, Box::new(move || self.run()));
+    }
+
+    fn run(mut self) {
+        let reading = {
+            let mut states = self.states.lock().unwrap();
+            let Some(state) = states.get_mut(&self.key) else {
+                return;
+            };
+            // Still moving, look again once it has been quiet for a while
+            if state.generation != self.generation {
+                self.generation = state.generation;
+                drop(states);
+                return self.schedule();
+            }
+            state.settling = false;
+            if !state.lagging(self.input_max) {
+                return;
+            }
+            state.value = state.reading as f64;
+            state.reading
+        };
+        // The same takeover check as the readings that came before
+        if let Action::Linear {
+            soft_takeover: true,
+            ..
+        } = self.action
+        {
+            if !self
+                .pickup
+                .lock()
+                .unwrap()
+                .engaged(&self.layer, self.key, reading)
+            {
+                return;
+            }
+        }
+        send_linear(
+            &self.action,
+            self.key,
+            reading,
+            self.input_max,
+            &self.last_knob_vals,
+            &self.last_linear_texts,
+            &self.commands,
+        );
+    }
+}
+
+// Like scale_linear, without rounding down to a whole number
+fn scale_linear_f64(input: u16, input_max: u16, min: f64, max: f64, invert: bool) -> f64 {
+    let (lo, hi) = if min > max { (max, min) } else { (min, max) };
+    let mut fraction = input.min(input_max) as f64 / input_max as f64;
+    if invert {
+        fraction = 1.0 - fraction;
+    }
+    lo + fraction * (hi - lo)
+}
+
+// True if `key` was last seen less than `window_ms` ago. Accepted triggers always restart the
+// window; `restart_on_drop` makes dropped ones restart it too, so a burst stays suppressed.
+fn too_soon(
+    last: &Mutex<HashMap<(u8, Control), Instant>>,
+    key: (u8, Control),
+    now: Instant,
+    window_ms: u64,
+    restart_on_drop: bool,
+) -> bool {
+    if window_ms == 0 {
+        return false;
+    }
+    let mut last = last.lock().unwrap();
+    let dropped = last
+        .get(&key)
+        .is_some_and(|prev| now.duration_since(*prev) < Duration::from_millis(window_ms));
+    if !dropped || restart_on_drop {
+        last.insert(key, now);
+    }
+    dropped
+}
+
+// Scale a step by how soon it followed the previous one: 1x at the edge of the window,
+// rising linearly to `factor` for steps that arrive back to back
+fn accelerate(
+    delta: i32,
+    acceleration: &Acceleration,
+    last: &Mutex<HashMap<ActionKey, Instant>>,
+    key: ActionKey,
+    now: Instant,
+) -> i32 {
+    let window = Duration::from_millis(
+        acceleration
+            .window_ms
+            .unwrap_or(DEFAULT_ACCELERATION_WINDOW_MS),
+    );
+    let Some(gap) = last
+        .lock()
+        .unwrap()
+        .insert(key, now)
+        .map(|prev| now.duration_since(prev))
+    else {
+        return delta;
+    };
+    if gap >= window {
+        return delta;
+    }
+    let speed = 1.0 - gap.as_secs_f64() / window.as_secs_f64();
+    let multiplier = 1.0 + (acceleration.factor - 1.0) * speed;
+    (delta as f64 * multiplier).round() as i32
+}
+
+// Signed step of a knob: decoded with `mode`, or compared to its previous absolute position
+fn control_step(
+    raw_val: u8,
+    mode: Option<RelativeMode>,
+    last: &Mutex<HashMap<(u8, Control, usize), u8>>,
+    key: (u8, Control, usize),
+) -> i32 {
+    match mode {
+        Some(mode) => decode_relative(raw_val, mode),
+        None => match last.lock().unwrap().insert(key, raw_val) {
+            // An endless encoder sending absolute values wraps around at the ends
+            Some(127) if raw_val == 0 => 1,
+            Some(0) if raw_val == 127 => -1,
+            Some(prev) => raw_val as i32 - prev as i32,
+            // First message only establishes the position
+            None => 0,
+        },
+    }
+}
+
+fn decode_relative(raw_val: u8, mode: RelativeMode) -> i32 {
+    let raw_val = (raw_val & 0x7f) as i32;
+    match mode {
+        RelativeMode::TwosComplement => {
+            if raw_val >= 64 {
+                raw_val - 128
+            } else {
+                raw_val
+            }
+        }
+        RelativeMode::SignedBit => {
+            let magnitude = raw_val & 0x3f;
+            if raw_val & 0x40 != 0 {
+                -magnitude
+            } else {
+                magnitude
+            }
+        }
+        RelativeMode::BinaryOffset => raw_val - 64,
+    }
+}
+
+// Prefer a channel-specific mapping, then fall back to one that matches any channel
+fn lookup_mapping(mappings: &Mappings, channel: u8, id: Control) -> Option<&Mapping> {
+    mappings
+        .get(&(Some(channel), id))
+        .or_else(|| mappings.get(&(None, id)))
+}
+
+// Translate evdev-style `KEY_*` names so configs work unchanged across platforms.
+// A bare single character (e.g. "@") is typed as-is using the current keyboard layout.
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn string_to_enigo_key(s: &str) -> Option<Key> {
+    let key = match s {
+        "KEY_F1" => Key::F1,
+        "KEY_F2" => Key::F2,
+        "KEY_F3" => Key::F3,
+        "KEY_F4" => Key::F4,
+        "KEY_F5" => Key::F5,
+        "KEY_F6" => Key::F6,
+        "KEY_F7" => Key::F7,
+        "KEY_F8" => Key::F8,
+        "KEY_F9" => Key::F9,
+        "KEY_F10" => Key::F10,
+        "KEY_F11" => Key::F11,
+        "KEY_F12" => Key::F12,
+        "KEY_F13" => Key::F13,
+        "KEY_F14" => Key::F14,
+        "KEY_F15" => Key::F15,
+        "KEY_F16" => Key::F16,
+        "KEY_F17" => Key::F17,
+        "KEY_F18" => Key::F18,
+        "KEY_F19" => Key::F19,
+        "KEY_F20" => Key::F20,
+        #[cfg(target_os = "windows")]
+        "KEY_F21" => Key::F21,
+        #[cfg(target_os = "windows")]
+        "KEY_F22" => Key::F22,
+        #[cfg(target_os = "windows")]
+        "KEY_F23" => Key::F23,
+        #[cfg(target_os = "windows")]
+        "KEY_F24" => Key::F24,
+
+        "KEY_UP" => Key::UpArrow,
+        "KEY_DOWN" => Key::DownArrow,
+        "KEY_LEFT" => Key::LeftArrow,
+        "KEY_RIGHT" => Key::RightArrow,
+        "KEY_HOME" => Key::Home,
+        "KEY_END" => Key::End,
+        "KEY_PAGEUP" => Key::PageUp,
+        "KEY_PAGEDOWN" => Key::PageDown,
+        #[cfg(target_os = "windows")]
+        "KEY_INSERT" => Key::Insert,
+        "KEY_DELETE" => Key::Delete,
+        "KEY_BACKSPACE" => Key::Backspace,
+        "KEY_ENTER" => Key::Return,
+        "KEY_TAB" => Key::Tab,
+        "KEY_SPACE" => Key::Space,
+        "KEY_ESC" => Key::Escape,
+        "KEY_CAPSLOCK" => Key::CapsLock,
+        "KEY_HELP" => Key::Help,
+        #[cfg(target_os = "windows")]
+        "KEY_NUMLOCK" => Key::Numlock,
+        #[cfg(target_os = "windows")]
+        "KEY_PAUSE" => Key::Pause,
+        #[cfg(target_os = "windows")]
+        "KEY_SYSRQ" => Key::PrintScr,
+
+        "KEY_LEFTCTRL" => Key::LControl,
+        "KEY_RIGHTCTRL" => Key::RControl,
+        "KEY_LEFTSHIFT" => Key::LShift,
+        "KEY_RIGHTSHIFT" => Key::RShift,
+        "KEY_LEFTALT" => Key::Alt,
+        #[cfg(target_os = "macos")]
+        "KEY_RIGHTALT" => Key::ROption,
+        #[cfg(target_os = "windows")]
+        "KEY_RIGHTALT" => Key::Alt,
+        "KEY_LEFTMETA" => Key::Meta,
+        #[cfg(target_os = "macos")]
+        "KEY_RIGHTMETA" => Key::RCommand,
+        #[cfg(target_os = "windows")]
+        "KEY_RIGHTMETA" => Key::Meta,
+
+        "KEY_PLAYPAUSE" => Key::MediaPlayPause,
+        "KEY_NEXTSONG" => Key::MediaNextTrack,
+        "KEY_PREVIOUSSONG" => Key::MediaPrevTrack,
+        #[cfg(target_os = "windows")]
+        "KEY_STOPCD" => Key::MediaStop,
+        #[cfg(target_os = "macos")]
+        "KEY_FASTFORWARD" => Key::MediaFast,
+        #[cfg(target_os = "macos")]
+        "KEY_REWIND" => Key::MediaRewind,
+        "KEY_VOLUMEUP" => Key::VolumeUp,
+        "KEY_VOLUMEDOWN" => Key::VolumeDown,
+        "KEY_MUTE" => Key::VolumeMute,
+
+        "KEY_MINUS" => Key::Unicode('-'),
+        "KEY_EQUAL" => Key::Unicode('='),
+        "KEY_LEFTBRACE" => Key::Unicode('['),
+        "KEY_RIGHTBRACE" => Key::Unicode(']'),
+        "KEY_SEMICOLON" => Key::Unicode(';'),
+        "KEY_APOSTROPHE" => Key::Unicode('\''),
+        "KEY_GRAVE" => Key::Unicode('`'),
+        "KEY_BACKSLASH" => Key::Unicode('\\'),
+        "KEY_COMMA" => Key::Unicode(','),
+        "KEY_DOT" => Key::Unicode('.'),
+        "KEY_SLASH" => Key::Unicode('/'),
+
+        _ => {
+            // KEY_A..KEY_Z and KEY_0..KEY_9, or a bare printable character
+            let (name, evdev_style) = match s.strip_prefix("KEY_") {
+                Some(name) => (name, true),
+                None => (s, false),
+            };
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if evdev_style && c.is_ascii_alphanumeric() => {
+                    Key::Unicode(c.to_ascii_lowercase())
+                }
+                (Some(c), None) if !evdev_style && !c.is_control() => Key::Unicode(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_can_be_shared_with_the_midi_callback() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Engine>();
+    }
+
+    #[test]
+    fn decode_rejects_short_messages() {
+        assert_eq!(decode(&[]), None);
+        assert_eq!(decode(&[0x90]), None);
+        assert_eq!(decode(&[0x90, 36]), None);
+        assert_eq!(decode(&[0x80, 36]), None);
+        assert_eq!(decode(&[0xB0, 7]), None);
+        assert_eq!(decode(&[0xC0]), None);
+        assert_eq!(decode(&[0xE0, 0]), None);
+    }
+
+    #[test]
+    fn decode_note_on_with_velocity_zero_is_note_off() {
+        assert_eq!(
+            decode(&[0x93, 36, 0]),
+            Some(MidiEvent::NoteOff {
+                channel: 3,
+                note: 36,
+                velocity: 0
+            })
+        );
+    }
+
+    #[test]
+    fn decode_each_status_byte() {
+        assert_eq!(
+            decode(&[0x80, 36, 64]),
+            Some(MidiEvent::NoteOff {
+                channel: 0,
+                note: 36,
+                velocity: 64
+            })
+        );
+        assert_eq!(
+            decode(&[0x91, 36, 100]),
+            Some(MidiEvent::NoteOn {
+                channel: 1,
+                note: 36,
+                velocity: 100
+            })
+        );
+        assert_eq!(
+            decode(&[0xA2, 40, 90]),
+            Some(MidiEvent::PolyPressure {
+                channel: 2,
+                note: 40,
+                pressure: 90
+            })
+        );
+        assert_eq!(
+            decode(&[0xBF, 7, 127]),
+            Some(MidiEvent::ControlChange {
+                channel: 15,
+                controller: 7,
+                value: 127
+            })
+        );
+        assert_eq!(
+            decode(&[0xC4, 5]),
+            Some(MidiEvent::ProgramChange {
+                channel: 4,
+                program: 5
+            })
+        );
+        assert_eq!(
+            decode(&[0xD5, 33]),
+            Some(MidiEvent::ChannelPressure {
+                channel: 5,
+                pressure: 33
+            })
+        );
+        assert_eq!(
+            decode(&[0xE6, 0x7f, 0x7f]),
+            Some(MidiEvent::PitchBend {
+                channel: 6,
+                value: PITCH_BEND_MAX
+            })
+        );
+    }
+
+    #[test]
+    fn decode_ignores_system_messages() {
+        assert_eq!(decode(&[0xF8]), None);
+        assert_eq!(decode(&[0xF0, 0x7e, 0x7f, 0x06, 0x01, 0xF7]), None);
+        assert_eq!(decode(&[0xF2, 0, 0]), None);
+        // A SysEx payload that happens to contain a Note On must not be read as one
+        assert_eq!(decode(&[0xF0, 0x90, 36, 100, 0xF7]), None);
+    }
+
+    #[test]
+    fn pitch_bend_keeps_full_resolution() {
+        let event = decode(&[0xE0, 0x00, 0x40]).unwrap();
+        assert_eq!(event.control(), Control::PitchBend);
+        assert_eq!(event.value(), (0x40, 8192, PITCH_BEND_MAX));
+    }
+
+    #[test]
+    fn events_map_to_controls() {
+        assert_eq!(decode(&[0x90, 36, 1]).unwrap().control(), Control::Id(36));
+        assert_eq!(decode(&[0xB0, 7, 1]).unwrap().control(), Control::Id(7));
+        assert_eq!(
+            decode(&[0xA0, 40, 1]).unwrap().control(),
+            Control::NotePressure(40)
+        );
+        assert_eq!(decode(&[0xD0, 1]).unwrap().control(), Control::Pressure);
+        // Program Change uses the program number as its value
+        assert_eq!(decode(&[0xC0, 12]).unwrap().value(), (12, 12, 127));
+    }
+
+    #[test]
+    fn relative_modes_decode_steps() {
+        use RelativeMode::*;
+        assert_eq!(decode_relative(1, TwosComplement), 1);
+        assert_eq!(decode_relative(127, TwosComplement), -1);
+        assert_eq!(decode_relative(1, SignedBit), 1);
+        assert_eq!(decode_relative(65, SignedBit), -1);
+        assert_eq!(decode_relative(65, BinaryOffset), 1);
+        assert_eq!(decode_relative(63, BinaryOffset), -1);
+        assert_eq!(decode_relative(67, BinaryOffset), 3);
+        assert_eq!(decode_relative(64, BinaryOffset), 0);
+    }
+
+    #[test]
+    fn built_in_profiles_name_valid_keys() {
+        for (name, _) in PROFILES {
+            let profile = load_profile(name, Path::new("config.toml")).unwrap();
+            for (control, key) in &profile.controls {
+                assert!(
+                    parse_mapping_key(&key.key()).is_ok(),
+                    "{}: {} = {}",
+                    name,
+                    control,
+                    key.key()
+                );
+            }
+        }
+    }
+
+    // A clock that only moves when a test says so
+    type Task = Box<dyn FnOnce() + Send>;
+
+    // A clock that only moves when told to, running the tasks that come due as it does
+    struct FakeClock {
+        now: Mutex<Instant>,
+        tasks: Mutex<Vec<(Instant, Task)>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                now: Mutex::new(Instant::now()),
+                tasks: Mutex::default(),
+            })
+        }
+
+        fn advance(&self, ms: u64) {
+            let now = {
+                let mut now = self.now.lock().unwrap();
+                *now += Duration::from_millis(ms);
+                *now
+            };
+            // Tasks may schedule more, which run too if they come due in time
+            loop {
+                let due = {
+                    let mut tasks = self.tasks.lock().unwrap();
+                    let Some(next) = (0..tasks.len())
+                        .filter(|&i| tasks[i].0 <= now)
+                        .min_by_key(|&i| tasks[i].0)
+                    else {
+                        break;
+                    };
+                    tasks.remove(next).1
+                };
+                due();
+            }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn after(&self, delay: Duration, task: Task) {
+            let due = self.now() + delay;
+            self.tasks.lock().unwrap().push((due, task));
+        }
+    }
+
+    // A dry-run engine for `mappings` on `clock`, whose commands stay queued (there are no
+    // workers) so they can be read back with `queued`
+    fn test_engine(mappings: &str, clock: &Arc<FakeClock>) -> Engine {
+        let config: MidiConfig =
+            toml::from_str(&format!("device_name = \"x\"\n[mappings]\n{}", mappings)).unwrap();
+        let mut engine = Engine::with_clock(&config, true, false, clock.clone()).unwrap();
+        engine.commands = CommandQueue::new(0, false, None);
+        engine
+    }
+
+    fn queued(engine: &Engine) -> Vec<String> {
+        let state = engine.commands.shared.0.lock().unwrap();
+        state
+            .pending
+            .iter()
+            .map(|job| job.cmd.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn debounce_window_restarts_on_every_hit() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"36 = { type = "Command", cmd = "hit", debounce_ms = 50 }"#,
+            &clock,
+        );
+        engine.handle_message(&[0x90, 36, 100]);
+        // Bounces 30ms apart keep pushing the window out, even 60ms after the first hit
+        clock.advance(30);
+        engine.handle_message(&[0x90, 36, 100]);
+        clock.advance(30);
+        engine.handle_message(&[0x90, 36, 100]);
+        assert_eq!(queued(&engine), ["hit"]);
+        clock.advance(50);
+        engine.handle_message(&[0x90, 36, 100]);
+        assert_eq!(queued(&engine), ["hit", "hit"]);
+    }
+
+    #[test]
+    fn cooldown_counts_from_the_last_fired_hit() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"36 = { type = "Command", cmd = "hit", cooldown_ms = 100 }"#,
+            &clock,
+        );
+        engine.handle_message(&[0x90, 36, 100]);
+        clock.advance(60);
+        engine.handle_message(&[0x90, 36, 100]);
+        clock.advance(39);
+        engine.handle_message(&[0x90, 36, 100]);
+        assert_eq!(queued(&engine), ["hit"]);
+        // Dropped hits don't extend the cooldown
+        clock.advance(1);
+        engine.handle_message(&[0x90, 36, 100]);
+        assert_eq!(queued(&engine), ["hit", "hit"]);
+    }
+
+    #[test]
+    fn press_picks_short_or_long_by_hold_time() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"36 = { type = "Press", short = { type = "Command", cmd = "short" }, long = { type = "Command", cmd = "long" }, threshold_ms = 500 }"#,
+            &clock,
+        );
+        engine.handle_message(&[0x90, 36, 100]);
+        clock.advance(499);
+        engine.handle_message(&[0x80, 36, 0]);
+        assert_eq!(queued(&engine), ["short"]);
+        engine.handle_message(&[0x90, 36, 100]);
+        clock.advance(500);
+        engine.handle_message(&[0x80, 36, 0]);
+        assert_eq!(queued(&engine), ["short", "long"]);
+    }
+
+    #[test]
+    fn smoothing_steadies_a_jittery_fader() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"7 = { type = "Linear", template = "a {}", min = 0, max = 127, dedup = false, smoothing = 0.9 }
+8 = { type = "Linear", template = "b {}", min = 0, max = 127, dedup = false }
+9 = { type = "Linear", template = "c {}", min = 0, max = 127, dedup = false, smoothing = 0.5 }
+10 = { type = "Linear", template = "d {}", min = 0, max = 127, smoothing = 0.9 }"#,
+            &clock,
+        );
+        let sent = |prefix: &str| -> Vec<String> {
+            queued(&engine)
+                .into_iter()
+                .filter_map(|cmd| cmd.strip_prefix(prefix).map(str::to_string))
+                .collect()
+        };
+        // A fader resting at 64 that reads up to 2 either side
+        let jitter = [64, 66, 62, 65, 63, 66, 62, 64, 65, 63, 66, 62];
+        for cc in [7, 8] {
+            for value in jitter {
+                engine.handle_message(&[0xB0, cc, value]);
+            }
+        }
+        assert_eq!(sent("a "), vec!["64"; jitter.len()]);
+        assert_eq!(
+            sent("b "),
+            ["64", "66", "62", "65", "63", "66", "62", "64", "65", "63", "66", "62"]
+        );
+        // A fader moved up and left alone trails its reading, and one pushed to the end doesn't
+        for value in [10, 20, 30, 40, 50] {
+            engine.handle_message(&[0xB0, 9, value]);
+            clock.advance(10);
+        }
+        engine.handle_message(&[0xB0, 10, 64]);
+        engine.handle_message(&[0xB0, 10, 127]);
+        assert_eq!(sent("c "), ["10", "15", "23", "31", "41"]);
+        assert_eq!(sent("d "), ["127"]);
+
+        // Once they have been still for SMOOTHING_SETTLE, only the moved fader catches up
+        clock.advance(SMOOTHING_SETTLE.as_millis() as u64 - 11);
+        assert_eq!(sent("c "), ["10", "15", "23", "31", "41"]);
+        clock.advance(SMOOTHING_SETTLE.as_millis() as u64);
+        assert_eq!(sent("a "), vec!["64"; jitter.len()]);
+        assert_eq!(sent("c "), ["10", "15", "23", "31", "41", "50"]);
+        assert_eq!(sent("d "), ["127"]);
+    }
+
+    #[test]
+    fn momentary_toggle_in_a_multi_is_rejected() {
+        let config: MidiConfig = toml::from_str(
+            r#"device_name = "x"
+[mappings]
+36 = { type = "Multi", actions = [{ type = "Toggle", on_cmd = "on", off_cmd = "off", mode = "momentary" }, { type = "Command", cmd = "hit" }] }"#,
+        )
+        .unwrap();
+        let Err(MidiActionError::InvalidConfig(problems)) = config.validate() else {
+            panic!("config should be invalid");
+        };
+        assert_eq!(
+            problems,
+            ["Mapping '36.actions[0]': a momentary toggle can't be part of a multi"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn long_running_commands_leave_the_worker_free() {
+        let queue = CommandQueue::new(1, false, None);
+        let source = (0, Control::Id(36));
+        queue.push(QueuedCommand {
+            timeout: Some(Duration::from_millis(1500)),
+            ..QueuedCommand::new(source, CommandLine::Shell("sleep 5".to_string()))
+        });
+        queue.push(QueuedCommand::new(
+            source,
+            CommandLine::Shell("exit 3".to_string()),
+        ));
+        let wait_for = |count, within| {
+            let deadline = Instant::now() + within;
+            while queue.failed.load(Ordering::Relaxed) < count {
+                assert!(Instant::now() < deadline, "{} commands never failed", count);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        // The second command runs while the first sleeps, well before its timeout, and the
+        // reaper still kills the first one
+        wait_for(1, Duration::from_secs(1));
+        wait_for(2, Duration::from_secs(3));
+        assert!(queue.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn default_skips_pitch_bend_and_pressure() {
+        let config: MidiConfig = toml::from_str(
+            r#"device_name = "x"
+default = { type = "Command", cmd = "unmapped {id}" }"#,
+        )
+        .unwrap();
+        let mut engine = Engine::with_clock(&config, true, false, FakeClock::new()).unwrap();
+        engine.commands = CommandQueue::new(0, false, None);
+        engine.handle_message(&[0xE0, 0, 64]);
+        engine.handle_message(&[0xD0, 100]);
+        engine.handle_message(&[0xA0, 36, 100]);
+        engine.handle_message(&[0x90, 36, 100]);
+        assert_eq!(queued(&engine), ["unmapped 36"]);
+    }
+
+    #[test]
+    fn high_res_applies_the_msb_without_waiting_for_the_lsb() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"7 = { type = "Linear", template = "{}", min = 0, max = 16383, dedup = false, high_res = { msb = 7, lsb = 39 } }"#,
+            &clock,
+        );
+        // Only MSBs, then an LSB refining the last one
+        engine.handle_message(&[0xB0, 7, 64]);
+        engine.handle_message(&[0xB0, 7, 65]);
+        engine.handle_message(&[0xB0, 39, 3]);
+        assert_eq!(queued(&engine), ["8192", "8320", "8323"]);
+    }
+
+    #[test]
+    fn an_explicit_id_beats_a_range_on_its_channel() {
+        let clock = FakeClock::new();
+        let engine = test_engine(
+            r#"36 = { type = "Command", cmd = "exact {id}" }
+"36-51" = { type = "Command", cmd = "range {id}", channel = 0 }"#,
+            &clock,
+        );
+        engine.handle_message(&[0x90, 36, 100]);
+        engine.handle_message(&[0x90, 37, 100]);
+        engine.handle_message(&[0x91, 36, 100]);
+        engine.handle_message(&[0x91, 37, 100]);
+        assert_eq!(queued(&engine), ["exact 36", "range 37", "exact 36"]);
+    }
+
+    #[test]
+    fn fader_group_members_cant_be_mapped_on_their_own() {
+        let config: MidiConfig = toml::from_str(
+            r#"device_name = "x"
+[mappings]
+"20-23" = { type = "FaderGroup", template = "eq {values}" }
+21 = { type = "Linear", template = "solo {}" }
+"0-127" = { type = "Command", cmd = "anything else" }"#,
+        )
+        .unwrap();
+        let Err(MidiActionError::InvalidConfig(problems)) = config.validate() else {
+            panic!("config should be invalid");
+        };
+        assert_eq!(
+            problems,
+            ["Mapping '21': overlaps FaderGroup '20-23', which needs every message from its members"]
+        );
+    }
+}
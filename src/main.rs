@@ -1,234 +1,911 @@
-use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
-#[cfg(any(target_os = "macos", target_os = "windows"))]
-use enigo::{Enigo, Key, KeyboardControllable};
-#[cfg(target_os = "linux")]
-use evdev::{
-    uinput::VirtualDeviceBuilder, AttributeSet, EventType as EvdevEventType, InputEvent,
-    Key as EvdevKey,
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use env_logger::WriteStyle;
+use log::{debug, error, info, warn, LevelFilter};
+#[cfg(unix)]
+use midi_actions::expand_env;
+use midi_actions::{
+    decode, include_paths, load_config, print_suggestion, print_sysex_suggestion, sysex_hex,
+    Control, DeviceMatcher, Engine, MidiActionError, MidiConfig, MidiEvent, Result, STDIN_CONFIG,
 };
-use midir::{Ignore, MidiInput};
-use serde::Deserialize;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use notify::{RecursiveMode, Watcher};
 use std::{
-    collections::HashMap,
+    ffi::OsString,
     fs,
-    process::Command,
-    sync::{Arc, Mutex, RwLock},
+    io::{self, IsTerminal, Write as _},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
+// Setup mode's table shows this many of the most recently discovered controls, so it
+// stays on one screen
+const SETUP_TABLE_ROWS: usize = 16;
+// How often the daemon checks whether the device was unplugged or has come back
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+// Time for the desktop to pick up a freshly created uinput device before `trigger` types on it
+#[cfg(target_os = "linux")]
+const UINPUT_SETTLE: Duration = Duration::from_millis(250);
+
 #[derive(Parser)]
 #[command(name = "midi-actions")]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
-    /// Path to the configuration file (default: config.toml)
-    #[arg(short, long)]
+    /// Path to the configuration file, or - for stdin (default: the user config dir, then
+    /// ./config.toml)
+    #[arg(short, long, global = true)]
     config: Option<String>,
+
+    /// Log each received event (-v) and unmatched messages too (-vv). RUST_LOG overrides this.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[arg(short, long, conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    /// Log the actions each event would trigger instead of pressing keys or running commands
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Also print controls that have no mapping, with a suggested mapping like setup mode
+    #[arg(long)]
+    learn: bool,
+
+    /// Exit after the first event that fires an action, e.g. "press any pad to continue"
+    #[arg(long)]
+    once: bool,
+
+    /// Stay in the foreground with plain timestamped logs, for systemd and other supervisors.
+    /// midi-actions never forks; this is implied when stderr isn't a terminal.
+    #[arg(long)]
+    foreground: bool,
+
+    /// Log as plain text or as one JSON object per line, for log collectors
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Setup,
-}
+    /// Print incoming MIDI events and suggested mappings
+    Setup {
+        /// Index of the port to listen on (as listed at startup)
+        #[arg(long, conflicts_with = "device")]
+        port: Option<usize>,
+
+        /// Listen on the first port whose name contains this text
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// List available MIDI input ports and exit
+    ListDevices,
+    /// Validate the config file and exit (non-zero on any problem)
+    Check,
+    /// Write a commented example config to --config, or print it when no path is given
+    Init {
+        /// Overwrite the file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Fire the action mapped to an id as if its MIDI message arrived, without the device
+    Trigger {
+        /// Mapping id: a note or CC number, "pitchbend", "pressure", "pressure:<note>" or "clock"
+        id: Control,
+
+        /// Velocity or CC value (default 127, or 16383 for pitchbend)
+        value: Option<u16>,
 
-#[derive(Deserialize, Debug, Clone)]
-struct MidiConfig {
-    device_name: String,
-    // Keys in TOML are always strings
-    mappings: HashMap<String, Action>,
+        /// MIDI channel of the message (0-15)
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..16))]
+        channel: u8,
+
+        /// Send a Control Change instead of a Note On
+        #[arg(long)]
+        cc: bool,
+
+        /// Keep the pad held this many milliseconds, then release it
+        #[arg(long)]
+        hold_ms: Option<u64>,
+    },
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-enum Action {
-    Key { code: String },
-    Command { cmd: String },
-    Linear { template: String },
+// Errors are reported through anyhow so their sources are printed too
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(level).parse_env("RUST_LOG");
+    if let LogFormat::Json = cli.log_format {
+        logger
+            .write_style(WriteStyle::Never)
+            .format(|buf, record| writeln!(buf, "{}", json_record(buf.timestamp_millis(), record)));
+    } else if cli.foreground || !io::stderr().is_terminal() {
+        // One uncoloured line per record so journald and log files stay readable
+        logger.write_style(WriteStyle::Never).format(|buf, record| {
+            writeln!(
+                buf,
+                "{} {:<5} {}",
+                buf.timestamp_millis(),
+                record.level(),
+                record.args()
+            )
+        });
+    }
+    logger.init();
+
+    let result = match cli.command {
+        Some(Commands::Setup { port, device }) => run_setup_mode(port, device.as_deref()),
+        Some(Commands::ListDevices) => list_devices(),
+        Some(Commands::Check) => check_config(cli.config.as_deref()),
+        Some(Commands::Init { force }) => init_config(cli.config.as_deref(), force),
+        Some(Commands::Trigger {
+            id,
+            value,
+            channel,
+            cc,
+            hold_ms,
+        }) => trigger(
+            cli.config.as_deref(),
+            cli.dry_run,
+            id,
+            value,
+            channel,
+            cc,
+            hold_ms,
+        ),
+        None => run_daemon_mode(cli.config.as_deref(), cli.dry_run, cli.learn, cli.once),
+    };
+    let Err(e) = result else { return Ok(()) };
+    let e = anyhow::Error::from(e);
+    if let LogFormat::Json = cli.log_format {
+        // A fatal error becomes one more record instead of breaking the JSON stream
+        error!("{:#}", e);
+        std::process::exit(1);
+    }
+    Err(e)
 }
 
-const NOTE_ON: u8 = 0x90;
-const CONTROL_CHANGE: u8 = 0xB0;
+// A log record as JSON: ts, level, target and msg, then the record's key-value fields
+// (event_type, id, value, action, result, ...) with numbers kept as numbers
+fn json_record(ts: impl std::fmt::Display, record: &log::Record) -> serde_json::Value {
+    use log::kv::{Error, Key, Value, VisitSource};
+    use serde_json::Map;
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    match cli.command {
-        Some(Commands::Setup) => run_setup_mode(),
-        None => run_daemon_mode(cli.config.as_deref()),
+    struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(
+            &mut self,
+            key: Key<'kvs>,
+            value: Value<'kvs>,
+        ) -> std::result::Result<(), Error> {
+            let value = match (value.to_u64(), value.to_i64()) {
+                (Some(n), _) => n.into(),
+                (None, Some(n)) => n.into(),
+                _ => value.to_string().into(),
+            };
+            self.0.insert(key.to_string(), value);
+            Ok(())
+        }
     }
+
+    let mut object = Map::new();
+    object.insert("ts".into(), ts.to_string().into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("msg".into(), record.args().to_string().into());
+    // The visitor never fails
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    object.into()
 }
 
 // --- SETUP MODE ---
-fn run_setup_mode() -> Result<()> {
+fn run_setup_mode(port_index: Option<usize>, device: Option<&str>) -> Result<()> {
     let mut midi_in = MidiInput::new("midi-actions-setup")?;
     midi_in.ignore(Ignore::None);
 
     let ports = midi_in.ports();
     if ports.is_empty() {
-        return Err(anyhow!("No MIDI devices found."));
+        return Err(MidiActionError::NoDevices);
     }
 
     println!("\n🎹 DISCOVERY MODE");
-    let port = &ports[ports.len() - 1];
+    let port = match (port_index, device) {
+        (Some(index), _) => ports.get(index).ok_or(MidiActionError::NoSuchPort {
+            index,
+            available: ports.len(),
+        })?,
+        (None, Some(device)) => ports
+            .iter()
+            .find(|p| midi_in.port_name(p).unwrap_or_default().contains(device))
+            .ok_or_else(|| MidiActionError::device_not_found(device, port_names(&midi_in)))?,
+        (None, None) if ports.len() == 1 => &ports[0],
+        (None, None) => &ports[prompt_for_port(&midi_in, &ports)?],
+    };
     println!("Listening to '{}'...", midi_in.port_name(port)?);
     println!("(Press Ctrl+C to stop)\n");
 
-    let _conn = midi_in.connect(port, "midir-setup", move |_stamp, msg, _| {
-        if msg.len() < 3 { return; }
+    let _conn = midi_in
+        .connect(
+            port,
+            "midir-setup",
+            move |_stamp, msg, discovery| discovery.observe(msg),
+            Discovery {
+                redraw: io::stdout().is_terminal(),
+                ..Discovery::default()
+            },
+        )
+        .map_err(|e| MidiActionError::MidiConnect(e.to_string()))?;
 
-        let msg_type = msg[0] & 0xf0;
-        let id = msg[1];
-        let val = msg[2];
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
 
-        // Debug output
-        println!("RAW: [{}, {}, {}] -> Type: {:#x}", msg[0], id, val, msg_type);
+// Controls setup mode has seen, in the order they were first touched. Each one prints its
+// suggested mapping once; after that only its row in the table changes.
+#[derive(Default)]
+struct Discovery {
+    controls: Vec<Discovered>,
+    // Keep a table at the bottom of the terminal, redrawn in place. Off when stdout is
+    // piped, so the output is just the snippets.
+    redraw: bool,
+    // Lines of the table currently on screen
+    drawn: usize,
+}
+
+struct Discovered {
+    channel: Option<u8>,
+    // The mapping key: an id, "pitchbend", ... or the SysEx bytes
+    key: String,
+    kind: &'static str,
+    value: Option<u16>,
+    hits: u64,
+}
+
+impl Discovery {
+    fn observe(&mut self, msg: &[u8]) {
+        let event = decode(msg);
+        let (channel, key, kind, value) = match event {
+            // Releases come after every press, there is nothing new in them
+            Some(MidiEvent::NoteOff { .. }) => return,
+            Some(event) => (
+                Some(event.channel()),
+                event.control().to_string(),
+                event.name(),
+                Some(event.value().1),
+            ),
+            None if msg.first() == Some(&0xF0) => (None, sysex_hex(msg), "SysEx", None),
+            None => return,
+        };
+        let seen = self
+            .controls
+            .iter()
+            .position(|c| c.channel == channel && c.key == key);
+
+        let mut out = io::stdout().lock();
+        if self.drawn > 0 {
+            // Back to the top of the old table and clear it
+            let _ = write!(out, "\x1b[{}A\x1b[J", self.drawn);
+        }
+        match seen {
+            Some(index) => {
+                let control = &mut self.controls[index];
+                control.value = value;
+                control.hits += 1;
+            }
+            None => {
+                match event {
+                    Some(event) => print_suggestion(msg, &event),
+                    None => print_sysex_suggestion(msg),
+                }
+                self.controls.push(Discovered {
+                    channel,
+                    key,
+                    kind,
+                    value,
+                    hits: 1,
+                });
+            }
+        }
+        if self.redraw {
+            self.drawn = self.draw(&mut out);
+        }
+        let _ = out.flush();
+    }
 
-        if msg_type == 0xB0 {
-             println!("# Knob Detected (ID: {})", id);
-             println!("\"{}\" = {{ type = \"Linear\", template = \"pactl set-sink-volume @DEFAULT_SINK@ {{}}%\" }}\n", id);
+    // Print the table, returning how many lines it took
+    fn draw(&self, out: &mut impl io::Write) -> usize {
+        let _ = writeln!(out, "Discovered controls: {}", self.controls.len());
+        let _ = writeln!(
+            out,
+            "  {:<4} {:<18} {:>6} {:>6}  KEY",
+            "CH", "TYPE", "VALUE", "HITS"
+        );
+        let shown = self.controls.len().min(SETUP_TABLE_ROWS);
+        let hidden = self.controls.len() - shown;
+        if hidden > 0 {
+            let _ = writeln!(out, "  ... {} earlier", hidden);
         }
-        else if msg_type == 0x90 && val > 0 {
-             println!("# Button Detected (ID: {})", id);
-             println!("\"{}\" = {{ type = \"Key\", code = \"KEY_F13\" }}\n", id);
+        for control in &self.controls[hidden..] {
+            let channel = control.channel.map(|c| c.to_string()).unwrap_or_default();
+            let value = control.value.map(|v| v.to_string()).unwrap_or_default();
+            // Long SysEx keys would wrap and throw off the redraw
+            let key: String = control.key.chars().take(40).collect();
+            let _ = writeln!(
+                out,
+                "  {:<4} {:<18} {:>6} {:>6}  {}",
+                channel, control.kind, value, control.hits, key
+            );
         }
-    }, ()).map_err(|e| anyhow!("Connection failed: {}", e))?;
+        2 + shown + usize::from(hidden > 0)
+    }
+}
+
+// --- LIST DEVICES ---
+fn list_devices() -> Result<()> {
+    let midi_in = MidiInput::new("midi-actions-list")?;
+    let ports = midi_in.ports();
+    if ports.is_empty() {
+        println!("No MIDI devices found.");
+    } else {
+        println!("Available MIDI ports:");
+        print_ports(&midi_in, &ports);
+    }
+    Ok(())
+}
+
+fn print_ports(midi_in: &MidiInput, ports: &[MidiInputPort]) {
+    for (i, port) in ports.iter().enumerate() {
+        let name = midi_in
+            .port_name(port)
+            .unwrap_or_else(|_| "<unknown>".into());
+        println!("  [{}] {}", i, name);
+    }
+}
 
+// Ask on stdin which port to use until a valid index is entered
+fn prompt_for_port(midi_in: &MidiInput, ports: &[MidiInputPort]) -> Result<usize> {
+    println!("Available MIDI ports:");
+    print_ports(midi_in, ports);
     loop {
-        std::thread::sleep(std::time::Duration::from_secs(60));
+        print!("Select a port [0-{}]: ", ports.len() - 1);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err(MidiActionError::NoPortSelected);
+        }
+        match line.trim().parse::<usize>() {
+            Ok(index) if index < ports.len() => return Ok(index),
+            _ => println!("Invalid selection '{}'", line.trim()),
+        }
+    }
+}
+
+// --- CHECK ---
+fn check_config(config_path: Option<&str>) -> Result<()> {
+    let config_path = resolve_config_path(config_path)?;
+    let config = load_config(&config_path)?;
+    let mappings = config.validate()?;
+    println!(
+        "✅ {} is valid ({} mappings)",
+        config_path.display(),
+        mappings
+    );
+    Ok(())
+}
+
+// Where to look for the config when --config isn't given, in order
+fn default_config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    {
+        if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+            paths.push(PathBuf::from(dir).join("midi-actions/config.toml"));
+        }
+        if let Some(home) = std::env::var_os("HOME") {
+            paths.push(PathBuf::from(home).join(".config/midi-actions/config.toml"));
+        }
+    }
+    // ~/Library/Application Support/midi-actions on macOS, %APPDATA%\midi-actions\config on Windows
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    if let Some(dirs) = directories::ProjectDirs::from("", "", "midi-actions") {
+        paths.push(dirs.config_dir().join("config.toml"));
+    }
+    paths.push(PathBuf::from("config.toml"));
+    paths
+}
+
+fn resolve_config_path(config_path: Option<&str>) -> Result<PathBuf> {
+    if let Some(path) = config_path {
+        return Ok(PathBuf::from(path));
+    }
+    let candidates = default_config_paths();
+    candidates
+        .iter()
+        .find(|path| path.is_file())
+        .cloned()
+        .ok_or(MidiActionError::NoConfig(candidates))
+}
+
+// --- INIT ---
+const EXAMPLE_CONFIG: &str = include_str!("example_config.toml");
+
+fn init_config(config_path: Option<&str>, force: bool) -> Result<()> {
+    let Some(config_path) = config_path.filter(|&path| path != STDIN_CONFIG) else {
+        print!("{}", EXAMPLE_CONFIG);
+        return Ok(());
+    };
+    if Path::new(config_path).exists() && !force {
+        return Err(MidiActionError::ConfigExists(PathBuf::from(config_path)));
+    }
+    fs::write(config_path, EXAMPLE_CONFIG)?;
+    println!("✅ Wrote example config to {}", config_path);
+    Ok(())
+}
+
+// --- TRIGGER ---
+fn trigger(
+    config_path: Option<&str>,
+    dry_run: bool,
+    control: Control,
+    value: Option<u16>,
+    channel: u8,
+    cc: bool,
+    hold_ms: Option<u64>,
+) -> Result<()> {
+    let config_path = resolve_config_path(config_path)?;
+    let config = load_config(&config_path)?;
+    let value = value.unwrap_or(if control == Control::PitchBend {
+        16383
+    } else {
+        127
+    });
+    let message = control.message(channel, value, cc)?;
+    let engine = Engine::new(&config, dry_run, false)?;
+    #[cfg(target_os = "linux")]
+    if !dry_run {
+        thread::sleep(UINPUT_SETTLE);
+    }
+
+    engine.handle_message(&message);
+    if engine.fired() == 0 {
+        return Err(MidiActionError::NotMapped { control, channel });
+    }
+    if let (Some(hold_ms), Control::Id(_)) = (hold_ms, control) {
+        thread::sleep(Duration::from_millis(hold_ms));
+        engine.handle_message(&control.message(channel, 0, cc)?);
     }
+    // Waits for the commands it started, like the daemon does on exit
+    engine.shutdown();
+    Ok(())
 }
 
 // --- DAEMON MODE ---
-fn run_daemon_mode(config_path: Option<&str>) -> Result<()> {
-    let config_path = config_path.unwrap_or("config.toml");
+fn run_daemon_mode(
+    config_path: Option<&str>,
+    dry_run: bool,
+    learn: bool,
+    once: bool,
+) -> Result<()> {
+    let config_path = resolve_config_path(config_path)?;
 
     // Load initial config
-    let config_str =
-        fs::read_to_string(&config_path).map_err(|_| anyhow!("{} not found!", config_path))?;
-    let config: MidiConfig = toml::from_str(&config_str)?;
-
-    // Create runtime mappings with u8 keys
-    let runtime_mappings: Arc<RwLock<HashMap<u8, Action>>> = Arc::new(RwLock::new(
-        config
-            .mappings
-            .into_iter()
-            .filter_map(|(k, v)| k.parse::<u8>().ok().map(|id| (id, v)))
-            .collect(),
-    ));
+    info!("Loading config from {}", config_path.display());
+    let config = load_config(&config_path)?;
+    let engine = Arc::new(Engine::new(&config, dry_run, learn)?);
+    let notifier = Notifier {
+        enabled: config.notifications,
+    };
+    // Piped in configs can't change, and re-reading stdin would find it empty
+    if config_path == Path::new(STDIN_CONFIG) {
+        info!("Config read from stdin, not watching for changes");
+    } else {
+        watch_config(&config_path, &config, Arc::clone(&engine), notifier)?;
+    }
+    // Kept open to notice the device going away and coming back
+    let probe = MidiInput::new("midi-actions-probe")?;
 
-    #[cfg(target_os = "linux")]
-    // 1. Setup Virtual Keyboard
-    let mut keys = AttributeSet::<EvdevKey>::new();
-    for action in runtime_mappings.read().unwrap().values() {
-        if let Action::Key { code } = action {
-            if let Ok(k) = code.parse::<EvdevKey>() {
-                keys.insert(k);
+    #[cfg(not(unix))]
+    if let Some(path) = &config.socket {
+        warn!(
+            "Control sockets aren't supported on this platform, ignoring socket = {}",
+            path
+        );
+    }
+    #[cfg(unix)]
+    let _socket = config
+        .socket
+        .as_deref()
+        .map(|path| {
+            serve_socket(
+                Path::new(&expand_env(path)),
+                SocketState {
+                    config_path: config_path.clone(),
+                    engine: Arc::clone(&engine),
+                    notifier,
+                },
+            )
+        })
+        .transpose()?;
+
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let quit_tx = stop_tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = stop_tx.send(());
+    })?;
+
+    // Connect. The callback is cloned for every reconnect, so the engine outlives a connection.
+    let on_message = {
+        let engine = Arc::clone(&engine);
+        move |_: u64, msg: &[u8], _: &mut ()| {
+            engine.handle_message(msg);
+            // A Quit action stops the daemon the same way a signal does
+            if engine.quit_requested() || (once && engine.fired() > 0) {
+                let _ = quit_tx.send(());
             }
         }
+    };
+    let device = engine.device();
+    let mut conn = Some(connect_input(device, on_message.clone())?);
+    if let Some((_, name)) = &conn {
+        notifier.send("MIDI device connected", name);
     }
-    #[cfg(target_os = "linux")]
-    let mut v_device = VirtualDeviceBuilder::new()?
-        .name("midi-actions")
-        .with_keys(&keys)?
-        .build()?;
 
-    // TODO: Setup PulseAudio context for native volume control
+    // Block until SIGINT/SIGTERM, checking on the device in between
+    let heartbeat = config
+        .heartbeat_secs
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let mut last_heartbeat = Instant::now();
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(RECONNECT_INTERVAL) {
+        if heartbeat.is_some_and(|interval| last_heartbeat.elapsed() >= interval) {
+            last_heartbeat = Instant::now();
+            info!(
+                "💓 Still listening{}, {}",
+                conn.as_ref()
+                    .map_or(String::new(), |(_, name)| format!(" on {}", name)),
+                counters(&engine)
+            );
+        }
+        let present = find_port(&probe, device).map(|(_, name)| name);
+        match &conn {
+            // A re-enumerated device can come back under a new port name before we look
+            Some((_, name)) if present.as_ref() != Some(name) => {
+                warn!("⚠️ {} disconnected, waiting for it to return", name);
+                notifier.send("MIDI device disconnected", name);
+                if let Some((conn, _)) = conn.take() {
+                    conn.close();
+                }
+                // Its Note Offs will never arrive
+                engine.release_held_keys();
+            }
+            None if present.is_some() => match connect_input(device, on_message.clone()) {
+                Ok(new_conn) => {
+                    notifier.send("MIDI device connected", &new_conn.1);
+                    // The device comes back with its LEDs off
+                    engine.reconnect_feedback();
+                    conn = Some(new_conn);
+                }
+                Err(e) => debug!("Reconnect failed: {}", e),
+            },
+            _ => {}
+        }
+    }
+    info!("Shutting down...");
+
+    if let Some((conn, _)) = conn {
+        conn.close();
+    }
+    engine.shutdown();
+    Ok(())
+}
 
-    // 2. Setup MIDI
-    let mut midi_in = MidiInput::new("midi-actions-daemon")?;
-    midi_in.ignore(Ignore::None);
-    let port = midi_in
+// What the engine has seen and done, for the heartbeat and `status`: events in total and by
+// type, actions fired and commands that failed
+fn counters(engine: &Engine) -> String {
+    let by_type: Vec<String> = engine
+        .event_counts()
+        .iter()
+        .map(|(kind, count)| format!("{} {}", count, kind))
+        .collect();
+    let by_type = if by_type.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", by_type.join(", "))
+    };
+    format!(
+        "{} events handled{}, {} actions fired, {} commands failed",
+        engine.events(),
+        by_type,
+        engine.fired(),
+        engine.failed_commands()
+    )
+}
+
+// Find the first input port that matches the configured device
+fn find_port(midi_in: &MidiInput, device: &DeviceMatcher) -> Option<(MidiInputPort, String)> {
+    midi_in.ports().into_iter().find_map(|p| {
+        let name = midi_in.port_name(&p).ok()?;
+        device.matches(&name).then_some((p, name))
+    })
+}
+
+fn port_names(midi_in: &MidiInput) -> Vec<String> {
+    midi_in
         .ports()
-        .into_iter()
-        .find(|p| {
-            midi_in
-                .port_name(p)
-                .unwrap_or_default()
-                .contains(&config.device_name)
-        })
-        .ok_or(anyhow!("Device '{}' not found", config.device_name))?;
+        .iter()
+        .filter_map(|p| midi_in.port_name(p).ok())
+        .collect()
+}
 
-    println!("✅ midi-actions Running on {}", midi_in.port_name(&port)?);
+// Open the device's input port, returning the connection and the port name it was made on
+fn connect_input<F>(
+    device: &DeviceMatcher,
+    callback: F,
+) -> Result<(MidiInputConnection<()>, String)>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    let mut midi_in = MidiInput::new("midi-actions-daemon")?;
+    midi_in.ignore(Ignore::None);
+    let (port, name) = find_port(&midi_in, device).ok_or_else(|| {
+        MidiActionError::device_not_found(&device.to_string(), port_names(&midi_in))
+    })?;
+    let conn = midi_in
+        .connect(&port, "midir-read", callback, ())
+        .map_err(|e| MidiActionError::MidiConnect(e.to_string()))?;
+    info!("✅ midi-actions Running on {}", name);
+    Ok((conn, name))
+}
 
-    let last_knob_vals = Arc::new(Mutex::new(HashMap::new()));
+// Re-parse the config whenever it or a file it includes changes and swap in the new mappings.
+// A broken file keeps the previous mappings so a typo never takes the daemon down. The
+// watcher lives as long as the daemon.
+fn watch_config(
+    path: &Path,
+    config: &MidiConfig,
+    engine: Arc<Engine>,
+    notifier: Notifier,
+) -> Result<()> {
+    let path = PathBuf::from(path);
+    let (file_names, mut dirs) = watch_list(&path, config)?;
+    let file_names = Arc::new(Mutex::new(file_names));
+    let watched = Arc::clone(&file_names);
+    let (changed_tx, changed) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let Ok(event) = res else { return };
+        if !(event.kind.is_modify() || event.kind.is_create()) {
+            return;
+        }
+        let file_names = watched.lock().unwrap();
+        if event.paths.iter().any(|p| {
+            p.file_name()
+                .is_some_and(|name| file_names.iter().any(|watched| watched == name))
+        }) {
+            let _ = changed_tx.send(());
+        }
+    })
+    .map_err(MidiActionError::Watch)?;
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(MidiActionError::Watch)?;
+    }
 
-    // 3. Connect
-    let _conn = midi_in
-        .connect(
-            &port,
-            "midir-read",
-            move |_, msg, _| {
-                if msg.len() < 3 {
-                    return;
+    // Reloads run here rather than in the watcher's callback, which must return before the
+    // watcher can take on the directories of new include files
+    thread::spawn(move || {
+        for () in changed {
+            let config = match reload_config(&path, &engine, notifier) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!(
+                        "Failed to reload {}: {} (keeping previous mappings)",
+                        path.display(),
+                        e
+                    );
+                    continue;
                 }
-
-                let msg_type = msg[0] & 0xf0;
-                let id = msg[1];
-                let raw_val = msg[2];
-
-                if (msg_type == NOTE_ON && raw_val > 0) || msg_type == CONTROL_CHANGE {
-                    if let Some(action) = runtime_mappings.read().unwrap().get(&id) {
-                        match action {
-                            Action::Key { code } => {
-                                #[cfg(target_os = "linux")]
-                                {
-                                    if let Ok(key) = code.parse::<EvdevKey>() {
-                                        if let Err(e) = v_device.emit(&[
-                                            InputEvent::new(EvdevEventType::KEY, key.code(), 1i32),
-                                            InputEvent::new(EvdevEventType::KEY, key.code(), 0i32),
-                                        ]) {
-                                            eprintln!("Failed to emit key: {}", e);
-                                        }
-                                    }
-                                }
-                                #[cfg(any(target_os = "macos", target_os = "windows"))]
-                                {
-                                    if let Some(key) = string_to_enigo_key(code) {
-                                        let mut enigo = Enigo::new();
-                                        if let Err(e) = enigo.key_click(key) {
-                                            eprintln!("Failed to simulate key: {}", e);
-                                        }
-                                    }
-                                }
-                            }
-                            Action::Command { cmd } => {
-                                if let Err(e) = Command::new("sh").arg("-c").arg(cmd).spawn() {
-                                    eprintln!("Failed to spawn command: {}", e);
-                                }
-                            }
-                            Action::Linear { template } => {
-                                let mut cache = last_knob_vals.lock().unwrap();
-                                let percent = (raw_val as f32 / 127.0 * 100.0) as u32;
-
-                                if cache.get(&id) != Some(&percent) {
-                                    let final_cmd = template.replace("{}", &percent.to_string());
-                                    if let Err(e) =
-                                        Command::new("sh").arg("-c").arg(final_cmd).spawn()
-                                    {
-                                        eprintln!("Failed to spawn volume command: {}", e);
-                                    }
-                                    cache.insert(id, percent);
-                                }
-                            }
-                        }
-                    }
+            };
+            // Include files may have been added or dropped
+            let (names, new_dirs) = match watch_list(&path, &config) {
+                Ok(list) => list,
+                Err(e) => {
+                    error!("Not updating the watched files: {}", e);
+                    continue;
                 }
-            },
-            (),
-        )
-        .map_err(|e| anyhow!("Connection failed: {}", e))?;
+            };
+            for dir in dirs.iter().filter(|dir| !new_dirs.contains(dir)) {
+                let _ = watcher.unwatch(dir);
+            }
+            for dir in new_dirs.iter().filter(|dir| !dirs.contains(dir)) {
+                if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    error!("Failed to watch {}: {}", dir.display(), e);
+                }
+            }
+            *file_names.lock().unwrap() = names;
+            dirs = new_dirs;
+        }
+    });
+    Ok(())
+}
 
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(60));
+// Names of the config and its include files, and the directories they are in. Editors often
+// save by replacing the file, so it's the directories that get watched rather than the inodes.
+fn watch_list(path: &Path, config: &MidiConfig) -> Result<(Vec<OsString>, Vec<PathBuf>)> {
+    let mut file_names = Vec::new();
+    let mut dirs = Vec::new();
+    for file in std::iter::once(path.to_path_buf()).chain(include_paths(path, config)) {
+        let file_name = file
+            .file_name()
+            .ok_or_else(|| MidiActionError::InvalidConfigPath(file.clone()))?
+            .to_owned();
+        file_names.push(file_name);
+        let dir = match file.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    Ok((file_names, dirs))
+}
+
+// Swap in the mappings from `path`, leaving the current ones in place if it doesn't load.
+// Returns the config it loaded.
+fn reload_config(path: &Path, engine: &Engine, notifier: Notifier) -> Result<MidiConfig> {
+    if path == Path::new(STDIN_CONFIG) {
+        return Err(MidiActionError::StdinReload);
+    }
+    let result = load_config(path).and_then(|config| engine.reload(&config).map(|()| config));
+    match &result {
+        Ok(_) => {
+            info!("🔄 Reloaded {}", path.display());
+            notifier.send("Config reloaded", &path.display().to_string());
+        }
+        Err(e) => notifier.send("Config not reloaded", &e.to_string()),
+    }
+    result
+}
+
+// Desktop notifications about the daemon itself, shown when the config sets `notifications`
+#[derive(Clone, Copy)]
+struct Notifier {
+    enabled: bool,
+}
+
+impl Notifier {
+    fn send(self, summary: &str, body: &str) {
+        if !self.enabled {
+            return;
+        }
+        let result = notify_rust::Notification::new()
+            .appname("midi-actions")
+            .summary(summary)
+            .body(body)
+            .show();
+        if let Err(e) = result {
+            debug!("Failed to show notification '{}': {}", summary, e);
+        }
+    }
+}
+
+/// What the control socket can see and change in the running daemon
+#[cfg(unix)]
+struct SocketState {
+    config_path: PathBuf,
+    engine: Arc<Engine>,
+    notifier: Notifier,
+}
+
+#[cfg(unix)]
+impl SocketState {
+    // Run one line from a client, returning the reply
+    fn handle(&self, line: &str) -> Result<String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("reload"), None, _) => {
+                reload_config(&self.config_path, &self.engine, self.notifier)?;
+                Ok(format!("reloaded {}", self.config_path.display()))
+            }
+            (Some("layer"), Some(name), None) => {
+                self.engine.set_layer(name)?;
+                Ok(format!("layer {}", name))
+            }
+            (Some("status"), None, _) => Ok(format!(
+                "layer {}, {} mappings, {}",
+                self.engine.active_layer(),
+                self.engine.mapping_count(),
+                counters(&self.engine)
+            )),
+            _ => Err(MidiActionError::UnknownCommand(line.to_string())),
+        }
     }
 }
 
-#[cfg(any(target_os = "macos", target_os = "windows"))]
-fn string_to_enigo_key(s: &str) -> Option<Key> {
-    match s {
-        "KEY_F13" => Some(Key::F13),
-        "KEY_F14" => Some(Key::F14),
-        // Add more as needed
-        _ => None,
+// Listen on `path` for control commands, one per line, each answered with `ok ...` or
+// `error: ...`. The socket file is removed again when the returned guard is dropped.
+#[cfg(unix)]
+fn serve_socket(path: &Path, state: SocketState) -> Result<SocketGuard> {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let socket_error = |error| MidiActionError::Socket {
+        path: path.to_path_buf(),
+        error,
+    };
+    let in_use = |reason: &str| socket_error(io::Error::new(io::ErrorKind::AddrInUse, reason));
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(in_use("the path exists and isn't a socket"));
+        }
+        // Only a socket left behind by a daemon that didn't shut down cleanly refuses
+        // connections; a live one belongs to another daemon
+        Ok(_) => match UnixStream::connect(path) {
+            Ok(_) => return Err(in_use("another daemon is already listening on it")),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("Removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+            Err(e) => return Err(socket_error(e)),
+        },
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path).map_err(socket_error)?;
+    info!("Listening for commands on {}", path.display());
+    let state = Arc::new(state);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // One thread per client, so a client that never sends a newline can't wedge the rest
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                let Ok(mut writer) = stream.try_clone() else {
+                    return;
+                };
+                for line in BufReader::new(stream).lines().map_while(|l| l.ok()) {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    debug!("Socket command: {}", line.trim());
+                    let reply = match state.handle(line.trim()) {
+                        Ok(reply) => format!("ok {}", reply),
+                        Err(e) => format!("error: {}", e),
+                    };
+                    if writeln!(writer, "{}", reply).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    Ok(SocketGuard(path.to_path_buf()))
+}
+
+/// Removes the control socket file on shutdown
+#[cfg(unix)]
+struct SocketGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
     }
 }